@@ -1,6 +1,61 @@
 extern crate cbindgen;
 
 use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Directory with one subdirectory per kernel release, each of which
+/// contains a (compressed) `linpmem.ko`
+const DRIVERS_DIR: &str = "drivers";
+const DRIVER_NAMES: [&str; 4] = [
+    "linpmem.ko",
+    "linpmem.ko.gz",
+    "linpmem.ko.xz",
+    "linpmem.ko.zst",
+];
+
+/// Drivers that the tests of the embedded drivers use
+const FIXTURE_DRIVERS_DIR: &str = "tests/fixtures/drivers";
+
+/// Generate the table of the driver objects in `drivers_dir`
+fn embed_drivers(drivers_dir: &str, output_file: &Path) {
+    println!("cargo:rerun-if-changed={}", drivers_dir);
+
+    let mut drivers = Vec::new();
+    for entry in fs::read_dir(drivers_dir)
+        .unwrap_or_else(|err| panic!("Cannot read {}: {}", drivers_dir, err))
+    {
+        let dir = entry.expect("Cannot read drivers directory").path();
+        let Some(release) = dir.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let Some(driver) = DRIVER_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+        else {
+            continue;
+        };
+
+        println!("cargo:rerun-if-changed={}", driver.display());
+        drivers.push(format!(
+            "    ({:?}, include_bytes!({:?})),\n",
+            release,
+            driver.canonicalize().expect("Cannot resolve driver path")
+        ));
+    }
+    drivers.sort();
+
+    fs::write(
+        output_file,
+        format!(
+            "/// Kernel releases and their driver objects\n\
+             pub static DRIVERS: &[(&str, &[u8])] = &[\n{}];\n",
+            drivers.concat()
+        ),
+    )
+    .expect("Cannot write table of embedded drivers");
+}
 
 fn main() {
     let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
@@ -8,6 +63,19 @@ fn main() {
     let output_dir = env::var("OUT_DIR").unwrap();
     let output_file = format!("{}/../../../lib{}", output_dir, package_name);
 
+    if env::var_os("CARGO_FEATURE_EMBEDDED_DRIVERS").is_some() {
+        println!("cargo:rerun-if-env-changed=PMEM_DRIVERS_DIR");
+        let drivers_dir = env::var("PMEM_DRIVERS_DIR")
+            .unwrap_or_else(|_| format!("{}/{}", crate_dir, DRIVERS_DIR));
+        let output_dir = Path::new(&output_dir);
+
+        embed_drivers(&drivers_dir, &output_dir.join("embedded_drivers.rs"));
+        embed_drivers(
+            &format!("{}/{}", crate_dir, FIXTURE_DRIVERS_DIR),
+            &output_dir.join("fixture_drivers.rs"),
+        );
+    }
+
     cbindgen::Builder::new()
         .with_crate(&crate_dir)
         .with_language(cbindgen::Language::C)
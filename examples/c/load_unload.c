@@ -3,6 +3,22 @@
 
 #include "../../target/x86_64-unknown-linux-musl/release/libpmem.h"
 
+static const char *describe(int ret)
+{
+	switch (ret) {
+	case PMEM_ERR_MODULE_VERMAGIC:
+		return "module was built for a different kernel";
+	case PMEM_ERR_MODULE_SIGNATURE:
+		return "module signature was rejected";
+	case PMEM_ERR_MODULE_LOCKDOWN:
+		return "loading modules is prohibited";
+	case PMEM_ERR_MODULE_REJECTED:
+		return "module was rejected";
+	default:
+		return strerror(-ret);
+	}
+}
+
 int main(int argc, char **argv)
 {
 	int ret;
@@ -15,7 +31,7 @@ int main(int argc, char **argv)
 
 	ret = pmem_load(argv[1]);
 	if (ret) {
-		fprintf(stderr, "error: %s\n", strerror(ret));
+		fprintf(stderr, "error: %s\n", describe(ret));
 		return -1;
 	}
 
@@ -25,7 +41,7 @@ int main(int argc, char **argv)
 
 	ret = pmem_unload();
 	if (ret) {
-		fprintf(stderr, "error: %s\n", strerror(ret));
+		fprintf(stderr, "error: %s\n", describe(ret));
 		return -1;
 	}
 
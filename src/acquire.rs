@@ -0,0 +1,680 @@
+//! Acquisition of all physical memory that is backed by RAM
+
+mod journal;
+
+use crate::acquire::journal::{Entry, Header, Journal};
+use crate::backend::{self, Backend};
+use crate::cli::{AcquireCli, BackendKind, ImageFormat, ProgressMode};
+use crate::image::{
+    aff4::{Aff4Writer, ChunkCompression},
+    compress::Encoder,
+    elf::ElfWriter,
+    lime::LimeWriter,
+    manifest::{Hashes, HashingWriter, Manifest, RangeHashes},
+    raw::{PaddedWriter, RawWriter},
+    split::{SplitIndex, SplitWriter},
+    ImageWriter, Metadata,
+};
+use crate::iomem::{Iomem, PhysRange};
+use crate::progress::{Progress, RangeOutcome};
+use crate::reader::{PhysReader, UnreadablePolicy};
+use anyhow::{bail, Context};
+use log::{debug, warn};
+use nix::sys::utsname;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Collect information about the host
+fn metadata(drv: &dyn Backend) -> anyhow::Result<Metadata> {
+    let init_cr3 = match drv.cr3(Some(1)) {
+        Ok(cr3) => Some(cr3),
+        Err(err) => {
+            warn!("Failed to query cr3 of init: {}", err);
+            None
+        }
+    };
+
+    Ok(Metadata {
+        kernel_release: utsname::uname()?
+            .release()
+            .to_string_lossy()
+            .into_owned(),
+        init_cr3,
+        time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    })
+}
+
+/// Journal of the current acquisition and chunks of an interrupted one
+struct Checkpoints {
+    format: ImageFormat,
+    journal: Option<Journal>,
+    /// Chunks that are already present in the output
+    replay: VecDeque<Entry>,
+    /// Output of the interrupted acquisition and offset of the next chunk
+    source: Option<(File, u64)>,
+}
+
+impl Checkpoints {
+    fn new(format: ImageFormat, journal: Option<Journal>) -> Self {
+        Self {
+            format,
+            journal,
+            replay: VecDeque::new(),
+            source: None,
+        }
+    }
+
+    /// Offset of a chunk in the output
+    fn offset(format: ImageFormat, entry: &Entry, position: u64) -> u64 {
+        match format {
+            ImageFormat::Padded => entry.address,
+            _ => position,
+        }
+    }
+
+    /// Continue the interrupted acquisition whose journal is at `path`
+    ///
+    /// Returns the output file, truncated after the last chunk that was
+    /// completely written.
+    fn resume(
+        path: &str,
+        output: &str,
+        header: &Header,
+    ) -> anyhow::Result<(File, Self)> {
+        let mut entries = Journal::load(path, header)?;
+        let source =
+            File::open(output).context(format!("Failed to open {}", output))?;
+
+        // The last chunk might have been written only partially
+        let mut end = 0;
+        let mut position = 0;
+        for (i, entry) in entries.iter().enumerate() {
+            let offset = Self::offset(header.format, entry, position);
+            position = offset + entry.size;
+
+            if i + 1 == entries.len() {
+                let mut data = vec![0u8; entry.size as usize];
+                if source.read_exact_at(&mut data, offset).is_ok()
+                    && entry.matches(&data)
+                {
+                    end = position;
+                }
+            } else {
+                end = position;
+            }
+        }
+        if end != position {
+            warn!("Discarding incomplete chunk at end of {}", output);
+            entries.pop();
+        }
+        debug!("Resuming after {} chunks", entries.len());
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(output)
+            .context(format!("Failed to open {}", output))?;
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+
+        let journal = Journal::rewrite(path, header, &entries)
+            .context(format!("Failed to rewrite journal {}", path))?;
+
+        Ok((
+            file,
+            Self {
+                format: header.format,
+                journal: Some(journal),
+                replay: entries.into(),
+                source: Some((source, 0)),
+            },
+        ))
+    }
+
+    /// Read the chunk at `address` if it is already present in the output
+    fn replay(&mut self, address: u64) -> anyhow::Result<Option<Vec<u8>>> {
+        let (Some(entry), Some((source, position))) =
+            (self.replay.front(), &mut self.source)
+        else {
+            return Ok(None);
+        };
+        if entry.address != address {
+            bail!("Journal has no chunk at 0x{:016x}", address);
+        }
+
+        let offset = Self::offset(self.format, entry, *position);
+        let mut data = vec![0u8; entry.size as usize];
+        source.read_exact_at(&mut data, offset)?;
+        if !entry.matches(&data) {
+            bail!("Chunk at 0x{:016x} does not match the journal", address);
+        }
+        *position = offset + entry.size;
+        self.replay.pop_front();
+
+        Ok(Some(data))
+    }
+
+    /// Record a chunk after it has been passed to `writer`
+    fn record(
+        &mut self,
+        writer: &mut dyn ImageWriter,
+        address: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if let Some(journal) = &mut self.journal {
+            writer.flush()?;
+            journal.record(&Entry::new(address, data))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read all bytes of `range` in chunks of at most `chunk_size` bytes
+fn acquire_range(
+    reader: &mut PhysReader,
+    range: &PhysRange,
+    chunk_size: u64,
+    writer: &mut HashingWriter,
+    checkpoints: &mut Checkpoints,
+    progress: &mut Progress,
+) -> anyhow::Result<RangeOutcome> {
+    let mut done = 0;
+    let mut replayed = 0;
+    let unreadable = reader.unreadable();
+
+    writer.begin_range(range)?;
+
+    while done < range.size() {
+        let address = range.start + done;
+
+        if let Some(data) = checkpoints.replay(address)? {
+            writer.replay(&data)?;
+            progress.skip(address, data.len() as u64);
+            done += data.len() as u64;
+            replayed += data.len() as u64;
+            continue;
+        }
+
+        let size = chunk_size.min(range.size() - done);
+        let mut mem = vec![0u8; size as usize];
+        reader.seek(SeekFrom::Start(address))?;
+        reader
+            .read_exact(&mut mem)
+            .context(format!("Failed to read 0x{:016x}", address))?;
+
+        writer.write_chunk(&mem)?;
+        checkpoints.record(writer, address, &mem)?;
+        progress.advance(address, size);
+        done += size;
+    }
+
+    let unreadable = reader.unreadable() - unreadable;
+    if unreadable != 0 {
+        warn!("{} bytes of {} were zero-filled", unreadable, range);
+        Ok(RangeOutcome::Failed)
+    } else if replayed == range.size() {
+        Ok(RangeOutcome::Skipped)
+    } else {
+        Ok(RangeOutcome::Read)
+    }
+}
+
+/// Hashes of an image and the spans of memory that were zero-filled
+struct Acquired {
+    ranges: Vec<RangeHashes>,
+    hashes: Hashes,
+    zero_filled: Vec<PhysRange>,
+}
+
+fn acquire_all(
+    drv: &dyn Backend,
+    ranges: &[PhysRange],
+    cli: &AcquireCli,
+    progress: Option<ProgressMode>,
+    writer: &mut dyn ImageWriter,
+    checkpoints: &mut Checkpoints,
+) -> Result<Acquired, Box<dyn Error>> {
+    let mut writer = HashingWriter::new(writer, &cli.hash);
+    let mut progress =
+        Progress::new(progress, ranges.iter().map(PhysRange::size).sum());
+    let end = ranges.iter().map(|r| r.end + 1).max().unwrap_or(0);
+    let mut reader = PhysReader::new(drv)
+        .with_end(end)
+        .with_chunk_size(cli.chunk_size)
+        .with_policy(if cli.zero_fill {
+            UnreadablePolicy::ZeroFill
+        } else {
+            UnreadablePolicy::Error
+        });
+
+    for range in ranges {
+        debug!("Acquiring {}", range);
+        let outcome = match acquire_range(
+            &mut reader,
+            range,
+            cli.chunk_size,
+            &mut writer,
+            checkpoints,
+            &mut progress,
+        ) {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                progress.end_range(RangeOutcome::Failed);
+                progress.finish();
+                return Err(err.into());
+            }
+        };
+        progress.end_range(outcome);
+    }
+    writer.finish()?;
+    progress.finish();
+
+    let (ranges, hashes) = writer.into_hashes();
+    Ok(Acquired {
+        ranges,
+        hashes,
+        zero_filled: reader.zero_filled().to_vec(),
+    })
+}
+
+pub fn run(
+    cli: &AcquireCli,
+    backend: BackendKind,
+    progress: Option<ProgressMode>,
+) -> Result<(), Box<dyn Error>> {
+    let ranges = Iomem::from_path(&cli.iomem)?.system_ram();
+    if ranges.is_empty() {
+        return Err(format!("No System RAM ranges in {}", cli.iomem).into());
+    }
+
+    let drv = backend::open(backend)?;
+
+    acquire(cli, drv.as_ref(), &ranges, progress)
+}
+
+/// Write an image of `ranges` and its manifest
+pub(crate) fn acquire(
+    cli: &AcquireCli,
+    drv: &dyn Backend,
+    ranges: &[PhysRange],
+    progress: Option<ProgressMode>,
+) -> Result<(), Box<dyn Error>> {
+    let header = Header {
+        format: cli.format,
+        sparse: cli.sparse,
+        compression: cli.compress,
+        split_size: cli.split_size,
+        chunk_size: cli.chunk_size,
+        ranges: ranges.to_vec(),
+    };
+    let journal_path = Journal::path_for(&cli.output);
+    let (output, mut checkpoints) = if cli.resume {
+        let (output, checkpoints) =
+            Checkpoints::resume(&journal_path, &cli.output, &header)?;
+        (Some(output), checkpoints)
+    } else if cli.split_size.is_some() {
+        (None, Checkpoints::new(cli.format, None))
+    } else {
+        let output = File::create(&cli.output)
+            .context(format!("Failed to create {}", cli.output))?;
+        let journal = if cli.journaled() {
+            Some(
+                Journal::create(&journal_path, &header)
+                    .context(format!("Failed to create {}", journal_path))?,
+            )
+        } else {
+            None
+        };
+        (Some(output), Checkpoints::new(cli.format, journal))
+    };
+    let output = output.map(BufWriter::new);
+    let mut split = match cli.split_size {
+        Some(split_size) => Some(SplitWriter::new(&cli.output, split_size)?),
+        None => None,
+    };
+
+    // Padded and AFF4 images need to seek, thus, the output cannot be
+    // compressed as a whole
+    let acquired = match cli.format {
+        ImageFormat::Padded => {
+            let output = output.expect("BUG: padded image without output");
+            let mut writer = PaddedWriter::new(output, cli.sparse);
+            acquire_all(
+                drv,
+                ranges,
+                cli,
+                progress,
+                &mut writer,
+                &mut checkpoints,
+            )?
+        }
+        ImageFormat::Aff4 => {
+            let output = output.expect("BUG: AFF4 image without output");
+            let mut writer = Aff4Writer::new(
+                output,
+                &metadata(drv)?,
+                ChunkCompression::try_from(cli.compress)?,
+            )?;
+            acquire_all(
+                drv,
+                ranges,
+                cli,
+                progress,
+                &mut writer,
+                &mut checkpoints,
+            )?
+        }
+        ImageFormat::Raw | ImageFormat::Lime | ImageFormat::Elf => {
+            let output: Box<dyn Write + '_> = match (&mut split, output) {
+                (Some(split), _) => Box::new(split),
+                (None, Some(output)) => Box::new(output),
+                (None, None) => unreachable!(),
+            };
+            let mut encoder = Encoder::new(output, cli.compress, cli.level)?;
+            let mut writer: Box<dyn ImageWriter + '_> = match cli.format {
+                ImageFormat::Raw => Box::new(RawWriter::new(&mut encoder)),
+                ImageFormat::Lime => Box::new(LimeWriter::new(&mut encoder)),
+                _ => Box::new(ElfWriter::new(
+                    &mut encoder,
+                    ranges,
+                    &metadata(drv)?,
+                )?),
+            };
+
+            let acquired = acquire_all(
+                drv,
+                ranges,
+                cli,
+                progress,
+                writer.as_mut(),
+                &mut checkpoints,
+            )?;
+            drop(writer);
+            encoder.finish()?;
+            if let Some(split) = &mut split {
+                split.finish()?;
+            }
+
+            acquired
+        }
+    };
+
+    let manifest = Manifest {
+        tool: format!("pmem {}", env!("CARGO_PKG_VERSION")),
+        image: Path::new(&cli.output)
+            .file_name()
+            .context("Output path has no file name")?
+            .to_string_lossy()
+            .into_owned(),
+        index: split.is_some().then(|| {
+            let index = SplitIndex::path_for(&cli.output);
+            Path::new(&index)
+                .file_name()
+                .expect("BUG: index path has no file name")
+                .to_string_lossy()
+                .into_owned()
+        }),
+        format: cli.format,
+        compression: cli.compress,
+        ranges: acquired.ranges,
+        hashes: acquired.hashes,
+        zero_filled: acquired.zero_filled,
+    };
+    let manifest_path = Manifest::path_for(&cli.output);
+    manifest
+        .store(&manifest_path)
+        .context(format!("Failed to write {}", manifest_path))?;
+
+    if checkpoints.journal.is_some() {
+        fs::remove_file(&journal_path)?;
+    }
+
+    let unreadable: u64 =
+        manifest.zero_filled.iter().map(PhysRange::size).sum();
+    if unreadable != 0 {
+        return Err(format!(
+            "{} bytes in {} spans could not be read and were zero-filled, see {}",
+            unreadable,
+            manifest.zero_filled.len(),
+            manifest_path
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::image::ImageBackend;
+    use crate::cli::HashAlgorithm;
+    use std::ops::Range;
+    use std::os::unix::fs::FileExt;
+
+    const RANGES: [PhysRange; 2] = [
+        PhysRange {
+            start: 0x1000,
+            end: 0x2fff,
+        },
+        PhysRange {
+            start: 0x5000,
+            end: 0x5fff,
+        },
+    ];
+
+    fn memory() -> ImageBackend {
+        let file = tempfile::tempfile().unwrap();
+        for (i, range) in RANGES.iter().enumerate() {
+            let data: Vec<u8> = (0..range.size())
+                .map(|j| (i as u64 * 7 + j % 251) as u8)
+                .collect();
+            file.write_all_at(&data, range.start).unwrap();
+        }
+
+        ImageBackend::detect(file).unwrap()
+    }
+
+    /// Memory with a span that cannot be read
+    struct Unreadable {
+        memory: ImageBackend,
+        span: Range<u64>,
+    }
+
+    impl Backend for Unreadable {
+        fn read_phys(&self, address: u64, size: u64) -> io::Result<Vec<u8>> {
+            if address < self.span.end && self.span.start < address + size {
+                return Err(io::Error::from_raw_os_error(14));
+            }
+            self.memory.read_phys(address, size)
+        }
+
+        fn v_to_p(&self, virt: u64, pid: Option<u32>) -> io::Result<u64> {
+            self.memory.v_to_p(virt, pid)
+        }
+
+        fn cr3(&self, pid: Option<u32>) -> io::Result<u64> {
+            self.memory.cr3(pid)
+        }
+    }
+
+    fn cli(output: &Path, format: ImageFormat, resume: bool) -> AcquireCli {
+        AcquireCli {
+            output: output.to_str().unwrap().to_string(),
+            format,
+            sparse: false,
+            compress: None,
+            level: None,
+            hash: vec![HashAlgorithm::Sha256],
+            split_size: None,
+            resume,
+            zero_fill: false,
+            iomem: String::new(),
+            chunk_size: 0x800,
+        }
+    }
+
+    fn header(cli: &AcquireCli) -> Header {
+        Header {
+            format: cli.format,
+            sparse: cli.sparse,
+            compression: cli.compress,
+            split_size: cli.split_size,
+            chunk_size: cli.chunk_size,
+            ranges: RANGES.to_vec(),
+        }
+    }
+
+    /// Leave the output and journal of an acquisition that was interrupted
+    /// while writing the chunk after the first `written` ones, and whose
+    /// journal records `recorded` chunks
+    fn interrupt(
+        cli: &AcquireCli,
+        image: &[u8],
+        written: usize,
+        recorded: usize,
+    ) {
+        let mut chunks = Vec::new();
+        let mut position = 0;
+        for range in &RANGES {
+            for address in (range.start..=range.end).step_by(0x800) {
+                let entry = Entry {
+                    address,
+                    size: 0x800,
+                    sha256: String::new(),
+                };
+                let offset = Checkpoints::offset(cli.format, &entry, position);
+                position = offset + 0x800;
+                let data = &image[offset as usize..position as usize];
+                chunks.push((offset, Entry::new(address, data)));
+            }
+        }
+
+        let (end, _) = chunks[written];
+        let mut output = image[..end as usize + 0x400].to_vec();
+        output[end as usize..].fill(0xaa);
+        fs::write(&cli.output, output).unwrap();
+
+        let path = Journal::path_for(&cli.output);
+        let mut journal = Journal::create(&path, &header(cli)).unwrap();
+        for (_, entry) in &chunks[..recorded] {
+            journal.record(entry).unwrap();
+        }
+        // The interruption left a partially written line
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"address\":")
+            .unwrap();
+    }
+
+    #[test]
+    fn resume() {
+        let backend = memory();
+
+        for format in [ImageFormat::Raw, ImageFormat::Padded] {
+            let dir = tempfile::tempdir().unwrap();
+            let reference = cli(&dir.path().join("reference"), format, false);
+            acquire(&reference, &backend, &RANGES, None).unwrap();
+            let image = fs::read(&reference.output).unwrap();
+            let manifest =
+                Manifest::load(&Manifest::path_for(&reference.output)).unwrap();
+
+            // Interrupted after the output was flushed, and before the
+            // journal was, e.g., by a crash of the system
+            for (written, recorded) in [(0, 0), (2, 2), (4, 4), (3, 4)] {
+                let cli = cli(&dir.path().join("memory"), format, true);
+                interrupt(&cli, &image, written, recorded);
+                acquire(&cli, &backend, &RANGES, None).unwrap();
+
+                assert_eq!(fs::read(&cli.output).unwrap(), image);
+                let resumed =
+                    Manifest::load(&Manifest::path_for(&cli.output)).unwrap();
+                assert_eq!(resumed.hashes, manifest.hashes);
+                assert!(!Path::new(&Journal::path_for(&cli.output)).exists());
+            }
+            assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4);
+        }
+    }
+
+    #[test]
+    fn truncated_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = memory();
+        let reference =
+            cli(&dir.path().join("reference"), ImageFormat::Raw, false);
+        acquire(&reference, &backend, &RANGES, None).unwrap();
+        let image = fs::read(&reference.output).unwrap();
+
+        // The journal records a chunk that was only written partially
+        let cli = cli(&dir.path().join("memory"), ImageFormat::Raw, true);
+        interrupt(&cli, &image, 3, 4);
+        let path = Journal::path_for(&cli.output);
+        let (output, checkpoints) =
+            Checkpoints::resume(&path, &cli.output, &header(&cli)).unwrap();
+
+        assert_eq!(output.metadata().unwrap().len(), 3 * 0x800);
+        assert_eq!(checkpoints.replay.len(), 3);
+        assert_eq!(Journal::load(&path, &header(&cli)).unwrap().len(), 3);
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn header_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = memory();
+        let reference =
+            cli(&dir.path().join("reference"), ImageFormat::Padded, false);
+        acquire(&reference, &backend, &RANGES, None).unwrap();
+        let image = fs::read(&reference.output).unwrap();
+
+        let mut cli =
+            cli(&dir.path().join("memory"), ImageFormat::Padded, true);
+        interrupt(&cli, &image, 2, 2);
+        let journal = fs::read(Journal::path_for(&cli.output)).unwrap();
+
+        cli.sparse = true;
+        assert!(acquire(&cli, &backend, &RANGES, None).is_err());
+        cli.sparse = false;
+        cli.chunk_size = 0x1000;
+        assert!(acquire(&cli, &backend, &RANGES, None).is_err());
+        cli.chunk_size = 0x800;
+        assert!(acquire(&cli, &backend, &RANGES[..1], None).is_err());
+
+        // The interrupted acquisition is left untouched
+        assert_eq!(fs::read(Journal::path_for(&cli.output)).unwrap(), journal);
+        assert_eq!(fs::read(&cli.output).unwrap().len(), 0x2400);
+    }
+
+    #[test]
+    fn unreadable_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Unreadable {
+            memory: memory(),
+            span: 0x2000..0x3000,
+        };
+        let reference =
+            cli(&dir.path().join("reference"), ImageFormat::Raw, false);
+        acquire(&reference, &backend.memory, &RANGES, None).unwrap();
+        let mut image = fs::read(&reference.output).unwrap();
+
+        // Reads fail unless zero-filling is requested
+        let mut cli = cli(&dir.path().join("memory"), ImageFormat::Raw, false);
+        assert!(acquire(&cli, &backend, &RANGES, None).is_err());
+        assert!(!Path::new(&Manifest::path_for(&cli.output)).exists());
+
+        cli.zero_fill = true;
+        assert!(acquire(&cli, &backend, &RANGES, None).is_err());
+        image[0x1000..0x2000].fill(0);
+        assert_eq!(fs::read(&cli.output).unwrap(), image);
+        let manifest =
+            Manifest::load(&Manifest::path_for(&cli.output)).unwrap();
+        assert_eq!(manifest.zero_filled, [PhysRange::new(0x2000, 0x2fff)]);
+    }
+}
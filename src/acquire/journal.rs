@@ -0,0 +1,131 @@
+//! Checkpoint journal that allows to resume interrupted acquisitions
+//!
+//! The journal is a file with one JSON object per line. The first line
+//! describes the acquisition, each of the following lines records a chunk
+//! that has been written to the output. Chunks are recorded after they have
+//! been flushed to the output file, i.e., the journal never runs ahead of
+//! the image unless the whole system crashes.
+
+use crate::cli::{Compression, ImageFormat};
+use crate::iomem::PhysRange;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Parameters that must not change when an acquisition is resumed, i.e.,
+/// all options that affect the layout of the output
+pub struct Header {
+    pub format: ImageFormat,
+    pub sparse: bool,
+    pub compression: Option<Compression>,
+    pub split_size: Option<u64>,
+    pub chunk_size: u64,
+    pub ranges: Vec<PhysRange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Physical address of the first byte of the chunk
+    pub address: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Entry {
+    pub fn new(address: u64, data: &[u8]) -> Self {
+        Self {
+            address,
+            size: data.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(data)),
+        }
+    }
+
+    /// Check that `data` is the content of this chunk
+    pub fn matches(&self, data: &[u8]) -> bool {
+        *self == Self::new(self.address, data)
+    }
+}
+
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Path of the journal that belongs to the image at `output`
+    pub fn path_for(output: &str) -> String {
+        format!("{}.journal", output)
+    }
+
+    /// Start a new journal, replacing any existing one
+    pub fn create(path: &str, header: &Header) -> io::Result<Self> {
+        let mut journal = Self {
+            file: File::create(path)?,
+        };
+
+        journal.append(header)?;
+
+        Ok(journal)
+    }
+
+    /// Replace the journal with one that records `entries`
+    ///
+    /// The new journal is written to a temporary file that is renamed, i.e.,
+    /// the old one stays intact until the new one is complete.
+    pub fn rewrite(
+        path: &str,
+        header: &Header,
+        entries: &[Entry],
+    ) -> io::Result<Self> {
+        let temp_path = format!("{}.tmp", path);
+        let mut journal = Self::create(&temp_path, header)?;
+
+        for entry in entries {
+            journal.record(entry)?;
+        }
+        journal.file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+
+        Ok(journal)
+    }
+
+    /// Read the chunks recorded in an existing journal
+    ///
+    /// A partially written last line is ignored.
+    pub fn load(path: &str, header: &Header) -> anyhow::Result<Vec<Entry>> {
+        let content = fs::read_to_string(path)
+            .context(format!("Failed to read journal {}", path))?;
+        let mut lines = content.lines();
+
+        let recorded: Header =
+            serde_json::from_str(lines.next().context("Journal is empty")?)
+                .context("Malformed journal header")?;
+        if recorded != *header {
+            bail!("Journal belongs to an acquisition with different ranges or options");
+        }
+
+        let mut entries = Vec::new();
+        for line in lines {
+            let Ok(entry) = serde_json::from_str(line) else {
+                break;
+            };
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+
+    fn append<T: Serialize>(&mut self, value: &T) -> io::Result<()> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+
+        self.file.write_all(&line)
+    }
+
+    /// Record a chunk that has been written to the output
+    pub fn record(&mut self, entry: &Entry) -> io::Result<()> {
+        self.append(entry)
+    }
+}
@@ -0,0 +1,125 @@
+//! Sources of physical memory
+//!
+//! Functionality that builds on reading physical memory, e.g., acquisition,
+//! only depends on the [`Backend`] trait. Thus, it works with the linpmem
+//! driver, with the interfaces that the kernel offers itself, and with images
+//! that were saved earlier.
+
+pub mod devmem;
+pub mod image;
+pub mod kcore;
+
+use crate::cli::{AccessMode, BackendKind};
+use crate::ioctl::Driver;
+use crate::iomem::Iomem;
+use crate::paging::PteInfo;
+use anyhow::Context;
+use log::debug;
+use std::error::Error;
+use std::io;
+
+pub trait Backend {
+    /// Read up to `size` bytes starting at physical `address`
+    ///
+    /// Implementations may return fewer bytes than requested.
+    fn read_phys(&self, address: u64, size: u64) -> io::Result<Vec<u8>>;
+
+    /// Read a single value of the width given by `mode`
+    ///
+    /// By default, this is a buffer read of the same size. Buffer reads must
+    /// use `read_phys` instead.
+    fn read_value(
+        &self,
+        address: u64,
+        mode: AccessMode,
+    ) -> io::Result<Vec<u8>> {
+        let size = mode.size().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Not a value mode")
+        })?;
+
+        self.read_phys(address, size as u64)
+    }
+
+    /// Translate `virt_address` in the address space of process `pid`
+    /// (default: current process)
+    fn v_to_p(&self, virt_address: u64, pid: Option<u32>) -> io::Result<u64>;
+
+    /// Translate `virt_address` with the page tables at `cr3`, which spares
+    /// looking up the cr3 of a process for each translation
+    fn v_to_p_cr3(&self, _virt_address: u64, _cr3: u64) -> io::Result<u64> {
+        Err(unsupported("Backend does not support address translation"))
+    }
+
+    /// Value of cr3 of process `pid` (default: current process)
+    fn cr3(&self, pid: Option<u32>) -> io::Result<u64>;
+
+    /// Entry that maps `virt_address` in the address space of process `pid`
+    /// (default: current process)
+    fn pte(
+        &self,
+        _virt_address: u64,
+        _pid: Option<u32>,
+    ) -> io::Result<PteInfo> {
+        Err(unsupported("Backend does not report page-table entries"))
+    }
+}
+
+fn unsupported(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
+fn open_devmem() -> anyhow::Result<devmem::DevMemBackend> {
+    let backend = devmem::DevMemBackend::open()
+        .context(format!("Failed to open {}", devmem::DEVMEM_PATH))?;
+    backend
+        .probe(&Iomem::load()?.system_ram())
+        .context(format!(
+        "Cannot read RAM through {}, is the kernel built with STRICT_DEVMEM?",
+        devmem::DEVMEM_PATH
+    ))?;
+
+    Ok(backend)
+}
+
+fn open_kcore() -> anyhow::Result<kcore::KcoreBackend> {
+    kcore::KcoreBackend::open()
+        .context(format!("Failed to open {}", kcore::KCORE_PATH))
+}
+
+/// Open the physical memory source selected on the command line
+///
+/// In automatic mode, the linpmem driver is preferred. If its device file
+/// cannot be opened, `/dev/mem` and `/proc/kcore` are tried in this order.
+///
+/// Errors of the driver are returned as they are, i.e., as `PmemError`, to
+/// keep their exit codes.
+pub fn open(kind: BackendKind) -> Result<Box<dyn Backend>, Box<dyn Error>> {
+    match kind {
+        BackendKind::Linpmem => Ok(Box::new(Driver::build()?)),
+        BackendKind::Devmem => Ok(Box::new(open_devmem()?)),
+        BackendKind::Kcore => Ok(Box::new(open_kcore()?)),
+        BackendKind::Auto => {
+            let mut errors = Vec::new();
+            let mut unavailable = |name: &str, err: anyhow::Error| {
+                debug!("Backend {} is unavailable: {:#}", name, err);
+                errors.push(format!("{}: {:#}", name, err));
+            };
+
+            match Driver::build() {
+                Ok(driver) => return Ok(Box::new(driver)),
+                Err(err) => unavailable("linpmem", err.into()),
+            }
+            match open_devmem() {
+                Ok(backend) => return Ok(Box::new(backend)),
+                Err(err) => unavailable("devmem", err),
+            }
+            match open_kcore() {
+                Ok(backend) => return Ok(Box::new(backend)),
+                Err(err) => unavailable("kcore", err),
+            }
+
+            Err(format!("No backend is available\n{}", errors.join("\n"))
+                .into())
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Physical memory that is read through `/dev/mem`
+//!
+//! The file offset equals the physical address. On kernels that are built
+//! with `CONFIG_STRICT_DEVMEM`, only the first megabyte of RAM and
+//! memory-mapped I/O regions can be read. Reads of other RAM pages fail with
+//! `EPERM`, while RAM in the first megabyte may read as zeros.
+
+use super::{unsupported, Backend};
+use crate::iomem::PhysRange;
+use crate::paging::PAGE_SIZE;
+use nix::errno::Errno;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+pub const DEVMEM_PATH: &str = "/dev/mem";
+
+/// Start of the RAM that is protected by `CONFIG_STRICT_DEVMEM`
+const STRICT_DEVMEM_START: u64 = 0x100000;
+
+/// Report reads that the kernel refused, as access is restricted, as such
+fn restricted(err: io::Error) -> io::Error {
+    match Errno::from_i32(err.raw_os_error().unwrap_or_default()) {
+        Errno::EPERM | Errno::EFAULT => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "System RAM above the first megabyte cannot be read ({}), \
+                 the kernel restricts /dev/mem",
+                err
+            ),
+        ),
+        _ => err,
+    }
+}
+
+pub struct DevMemBackend {
+    file: File,
+}
+
+impl DevMemBackend {
+    pub fn open() -> io::Result<Self> {
+        Self::from_path(DEVMEM_PATH)
+    }
+
+    pub fn from_path(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+        })
+    }
+
+    /// Check that RAM in `ranges` can be read, i.e., that access is not
+    /// restricted by `CONFIG_STRICT_DEVMEM`
+    ///
+    /// The first page of RAM above the first megabyte is read, which fails
+    /// if access is restricted.
+    pub fn probe(&self, ranges: &[PhysRange]) -> io::Result<()> {
+        let Some(page) = ranges.iter().find_map(|r| {
+            let start = r.start.max(STRICT_DEVMEM_START);
+            let start = start.checked_next_multiple_of(PAGE_SIZE)?;
+            (start.checked_add(PAGE_SIZE - 1)? <= r.end).then_some(start)
+        }) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No System RAM page above the first megabyte",
+            ));
+        };
+
+        let data = self.read_phys(page, PAGE_SIZE).map_err(restricted)?;
+        if data.len() as u64 != PAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Short read of the page at 0x{:016x}", page),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for DevMemBackend {
+    fn read_phys(&self, address: u64, size: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; size as usize];
+        let read = self.file.read_at(&mut buf, address)?;
+        buf.truncate(read);
+
+        Ok(buf)
+    }
+
+    fn v_to_p(&self, _virt_address: u64, _pid: Option<u32>) -> io::Result<u64> {
+        Err(unsupported("/dev/mem does not support address translation"))
+    }
+
+    fn cr3(&self, _pid: Option<u32>) -> io::Result<u64> {
+        Err(unsupported("/dev/mem does not support querying cr3"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        file.as_file().set_len(0x200000).unwrap();
+        let backend =
+            DevMemBackend::from_path(file.path().to_str().unwrap()).unwrap();
+        let ranges = [
+            PhysRange::new(0x1000, 0x9ffff),
+            PhysRange::new(0x100800, 0x1fffff),
+        ];
+
+        // Pages of zeros are readable, only complete pages are probed
+        backend.probe(&ranges).unwrap();
+        backend
+            .probe(&[PhysRange::new(0x100800, 0x101fff)])
+            .unwrap();
+        assert_eq!(
+            backend
+                .probe(&[PhysRange::new(0x100800, 0x100fff)])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        // Beyond the end of the file
+        assert_eq!(
+            backend
+                .probe(&[PhysRange::new(0x200000, 0x2fffff)])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        assert_eq!(
+            backend.probe(&ranges[..1]).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let err = io::Error::from_raw_os_error(Errno::EPERM as i32);
+        assert_eq!(restricted(err).kind(), io::ErrorKind::PermissionDenied);
+        let err = io::Error::from_raw_os_error(Errno::EIO as i32);
+        assert_eq!(restricted(err).raw_os_error(), Some(Errno::EIO as i32));
+    }
+}
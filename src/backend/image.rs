@@ -0,0 +1,295 @@
+//! Physical memory that is served from an image file
+//!
+//! Images must be uncompressed and stored in a single file. The format of an
+//! image is taken from its manifest if there is one. Otherwise, LiME and ELF
+//! images are recognized by their magic and all other files are treated as
+//! padded images, i.e., the file offset equals the physical address.
+
+use super::{unsupported, Backend};
+use crate::cli::ImageFormat;
+use crate::image::lime::{LimeReader, LIME_MAGIC};
+use crate::image::manifest::Manifest;
+use crate::image::{elf, read_segments, Segment};
+use crate::iomem::PhysRange;
+use crate::paging::{PageTableWalker, PagingMode};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+pub struct ImageBackend {
+    file: File,
+    segments: Vec<Segment>,
+    /// Value of cr3 of the init process, if recorded in the image
+    init_cr3: Option<u64>,
+    /// Paging mode of the imaged machine, which images do not record
+    paging_mode: PagingMode,
+}
+
+impl ImageBackend {
+    /// Open the image at `path`, using its manifest if it exists
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+
+        if !Path::new(&Manifest::path_for(path)).exists() {
+            return Self::detect(file);
+        }
+
+        let manifest = Manifest::load(&Manifest::path_for(path))?;
+        if manifest.compression.is_some() || manifest.index.is_some() {
+            return Err(unsupported(
+                "Compressed and split images cannot be read directly",
+            ));
+        }
+
+        match manifest.format {
+            ImageFormat::Raw => {
+                let mut segments = Vec::new();
+                let mut offset = 0;
+                for range in manifest.ranges.iter().map(|r| r.range()) {
+                    segments.push(Segment { range, offset });
+                    offset += range.size();
+                }
+
+                Ok(Self {
+                    file,
+                    segments,
+                    init_cr3: None,
+                    paging_mode: PagingMode::FourLevel,
+                })
+            }
+            ImageFormat::Padded => Ok(Self {
+                file,
+                segments: manifest
+                    .ranges
+                    .iter()
+                    .map(|r| Segment {
+                        range: r.range(),
+                        offset: r.start,
+                    })
+                    .collect(),
+                init_cr3: None,
+                paging_mode: PagingMode::FourLevel,
+            }),
+            ImageFormat::Lime => Self::lime(file),
+            ImageFormat::Elf => Self::elf(file),
+            ImageFormat::Aff4 => {
+                Err(unsupported("AFF4 images cannot be read directly"))
+            }
+        }
+    }
+
+    /// Guess the format of an image from its first bytes
+    pub fn detect(mut file: File) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        let size = file.read(&mut magic)?;
+
+        if size == magic.len() && magic == *ELF_MAGIC {
+            Self::elf(file)
+        } else if size == magic.len() && magic == LIME_MAGIC.to_le_bytes() {
+            Self::lime(file)
+        } else {
+            Self::padded(file)
+        }
+    }
+
+    fn padded(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        let segments = match size {
+            0 => Vec::new(),
+            _ => vec![Segment {
+                range: PhysRange::new(0, size - 1),
+                offset: 0,
+            }],
+        };
+
+        Ok(Self {
+            file,
+            segments,
+            init_cr3: None,
+            paging_mode: PagingMode::FourLevel,
+        })
+    }
+
+    fn lime(file: File) -> io::Result<Self> {
+        let segments = LimeReader::new(&file)?.segments().to_vec();
+
+        Ok(Self {
+            file,
+            segments,
+            init_cr3: None,
+            paging_mode: PagingMode::FourLevel,
+        })
+    }
+
+    fn elf(mut file: File) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+        let (segments, _) = elf::parse_headers(&mut file)?;
+        let init_cr3 = elf::read_note(&mut file, elf::NT_PMEM_INIT_CR3)?
+            .and_then(|desc| desc.try_into().ok())
+            .map(u64::from_le_bytes);
+
+        Ok(Self {
+            file,
+            segments,
+            init_cr3,
+            paging_mode: PagingMode::FourLevel,
+        })
+    }
+
+    /// Physical memory ranges stored in the image
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Walk the page tables in the image with `mode` (default: 4-level
+    /// paging)
+    pub fn with_paging_mode(mut self, mode: PagingMode) -> Self {
+        self.paging_mode = mode;
+        self
+    }
+}
+
+impl Backend for ImageBackend {
+    /// Reads stop at the end of the range that contains `address`
+    fn read_phys(&self, address: u64, size: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; size as usize];
+        let read =
+            read_segments(&mut &self.file, &self.segments, address, &mut buf)?;
+        buf.truncate(read);
+
+        Ok(buf)
+    }
+
+    /// Only the address space of the init process can be translated
+    fn v_to_p(&self, virt_address: u64, pid: Option<u32>) -> io::Result<u64> {
+        self.v_to_p_cr3(virt_address, self.cr3(pid)?)
+    }
+
+    /// Walk the page tables in the image
+    fn v_to_p_cr3(&self, virt_address: u64, cr3: u64) -> io::Result<u64> {
+        PageTableWalker::new(self, cr3, self.paging_mode)
+            .translate(virt_address)?
+            .map(|translation| translation.phys_address)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Address 0x{:016x} is not mapped", virt_address),
+                )
+            })
+    }
+
+    /// Only the value of cr3 of the init process is known
+    fn cr3(&self, pid: Option<u32>) -> io::Result<u64> {
+        match (pid, self.init_cr3) {
+            (Some(1), Some(cr3)) => Ok(cr3),
+            (Some(1), None) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Image does not record the cr3 of init",
+            )),
+            _ => Err(unsupported("Images only record the cr3 of init")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::elf::ElfWriter;
+    use crate::image::lime::LimeWriter;
+    use crate::image::{ImageWriter, Metadata};
+    use std::io::Seek;
+    use std::os::unix::fs::FileExt;
+
+    fn write_image(writer: impl FnOnce(&mut File)) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        writer(&mut file);
+        file.rewind().unwrap();
+
+        file
+    }
+
+    #[test]
+    fn lime() {
+        let ranges = [
+            PhysRange::new(0x1000, 0x1fff),
+            PhysRange::new(0x4000, 0x4fff),
+        ];
+        let file = write_image(|file| {
+            let mut writer = LimeWriter::new(file);
+            for (i, range) in ranges.iter().enumerate() {
+                writer.begin_range(range).unwrap();
+                writer.write_chunk(&vec![i as u8 + 1; 0x1000]).unwrap();
+            }
+            writer.finish().unwrap();
+        });
+
+        let backend = ImageBackend::detect(file).unwrap();
+        assert_eq!(backend.segments().len(), 2);
+        assert_eq!(backend.read_phys(0x1ffe, 0x10).unwrap(), vec![1, 1]);
+        assert_eq!(backend.read_phys(0x4000, 2).unwrap(), vec![2, 2]);
+        assert!(backend.read_phys(0x2000, 1).is_err());
+        assert!(backend.cr3(Some(1)).is_err());
+    }
+
+    #[test]
+    fn elf() {
+        let ranges = [PhysRange::new(0x1000, 0x1fff)];
+        let metadata = Metadata {
+            kernel_release: String::from("6.5.0"),
+            init_cr3: Some(0x1234000),
+            time: 0,
+        };
+        let file = write_image(|file| {
+            let mut writer = ElfWriter::new(file, &ranges, &metadata).unwrap();
+            writer.begin_range(&ranges[0]).unwrap();
+            writer.write_chunk(&[0xaa; 0x1000]).unwrap();
+            writer.finish().unwrap();
+        });
+
+        let backend = ImageBackend::detect(file).unwrap();
+        assert_eq!(backend.read_phys(0x1800, 1).unwrap(), vec![0xaa]);
+        assert_eq!(backend.cr3(Some(1)).unwrap(), 0x1234000);
+        assert!(backend.cr3(None).is_err());
+    }
+
+    #[test]
+    fn padded() {
+        let file = write_image(|file| {
+            file.write_all_at(b"\0\0\0\0pmem", 0).unwrap();
+        });
+
+        let backend = ImageBackend::detect(file).unwrap();
+        assert_eq!(backend.read_phys(4, 0x100).unwrap(), b"pmem");
+    }
+
+    #[test]
+    fn translation() {
+        // 4K page at 0x40_0000 with the tables at 0x1000 (PML4), 0x2000
+        // (PDPT), 0x3000 (PD), and 0x4000 (PT)
+        let file = write_image(|file| {
+            file.set_len(0x6000).unwrap();
+            for (address, value) in [
+                (0x1000, 0x2003u64),
+                (0x2000, 0x3003),
+                (0x3000 + 2 * 8, 0x4003),
+                (0x4000, 0x5003),
+            ] {
+                file.write_all_at(&value.to_le_bytes(), address).unwrap();
+            }
+        });
+
+        let backend = ImageBackend::detect(file).unwrap();
+        assert_eq!(backend.v_to_p_cr3(0x40_0123, 0x1000).unwrap(), 0x5123);
+        assert_eq!(
+            backend.v_to_p_cr3(0x40_1000, 0x1000).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        // Without a recorded cr3, processes cannot be translated
+        assert!(backend.v_to_p(0x40_0123, Some(1)).is_err());
+
+        let backend = backend.with_paging_mode(PagingMode::FiveLevel);
+        assert!(backend.v_to_p_cr3(0x40_0123, 0x1000).is_err());
+    }
+}
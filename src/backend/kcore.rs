@@ -0,0 +1,136 @@
+//! Physical memory that is read through `/proc/kcore`
+//!
+//! `/proc/kcore` is an ELF core file of the kernel's virtual address space.
+//! For segments that map RAM, i.e., the direct map and the kernel image, the
+//! kernel stores the physical address in `p_paddr`. All other segments have
+//! a `p_paddr` of -1 and are ignored.
+
+use super::{unsupported, Backend};
+use crate::image::elf::{self, PT_LOAD};
+use crate::image::{read_segments, Segment};
+use crate::iomem::PhysRange;
+use std::fs::File;
+use std::io::{self, BufReader};
+
+pub const KCORE_PATH: &str = "/proc/kcore";
+
+/// Value of `p_paddr` for segments without a physical address
+const NO_PADDR: u64 = u64::MAX;
+
+pub struct KcoreBackend {
+    file: File,
+    segments: Vec<Segment>,
+}
+
+impl KcoreBackend {
+    pub fn open() -> io::Result<Self> {
+        Self::from_path(KCORE_PATH)
+    }
+
+    pub fn from_path(path: &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mut segments: Vec<_> =
+            elf::program_headers(&mut BufReader::new(&file))?
+                .into_iter()
+                .filter(|h| {
+                    h.p_type == PT_LOAD
+                        && h.p_paddr != NO_PADDR
+                        && h.p_filesz != 0
+                        && h.p_paddr.checked_add(h.p_filesz).is_some()
+                })
+                .map(|h| Segment {
+                    range: PhysRange::new(
+                        h.p_paddr,
+                        h.p_paddr + h.p_filesz - 1,
+                    ),
+                    offset: h.p_offset,
+                })
+                .collect();
+        segments.sort_by_key(|s| s.range.start);
+
+        if segments.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No segments with physical addresses",
+            ));
+        }
+
+        Ok(Self { file, segments })
+    }
+
+    /// Physical memory ranges that can be read
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
+
+impl Backend for KcoreBackend {
+    /// Reads stop at the end of the segment that contains `address`
+    fn read_phys(&self, address: u64, size: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; size as usize];
+        let read =
+            read_segments(&mut &self.file, &self.segments, address, &mut buf)?;
+        buf.truncate(read);
+
+        Ok(buf)
+    }
+
+    fn v_to_p(&self, _virt_address: u64, _pid: Option<u32>) -> io::Result<u64> {
+        Err(unsupported(
+            "/proc/kcore does not support address translation",
+        ))
+    }
+
+    fn cr3(&self, _pid: Option<u32>) -> io::Result<u64> {
+        Err(unsupported("/proc/kcore does not support querying cr3"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn phdr(p_offset: u64, p_vaddr: u64, p_paddr: u64, size: u64) -> Vec<u8> {
+        let mut phdr = Vec::new();
+        phdr.extend_from_slice(&PT_LOAD.to_le_bytes());
+        phdr.extend_from_slice(&7u32.to_le_bytes());
+        for field in [p_offset, p_vaddr, p_paddr, size, size, 0x1000] {
+            phdr.extend_from_slice(&field.to_le_bytes());
+        }
+
+        phdr
+    }
+
+    #[test]
+    fn physical_segments() {
+        let mut kcore = b"\x7fELF\x02\x01\x01\x00".to_vec();
+        kcore.resize(32, 0);
+        kcore.extend_from_slice(&elf::EHDR_SIZE.to_le_bytes());
+        kcore.resize(54, 0);
+        kcore.extend_from_slice(&(elf::PHDR_SIZE as u16).to_le_bytes());
+        kcore.extend_from_slice(&2u16.to_le_bytes());
+        kcore.resize(elf::EHDR_SIZE as usize, 0);
+        // vmalloc area without physical address, followed by the direct map
+        kcore.extend(phdr(0x1000, 0xffffc90000000000, NO_PADDR, 0x1000));
+        kcore.extend(phdr(0x2000, 0xffff888000100000, 0x100000, 0x1000));
+        kcore.resize(0x2000, 0);
+        kcore.extend_from_slice(b"pmem");
+        kcore.resize(0x3000, 0);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), &kcore).unwrap();
+        let backend =
+            KcoreBackend::from_path(file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            backend.segments(),
+            [Segment {
+                range: PhysRange::new(0x100000, 0x100fff),
+                offset: 0x2000,
+            }]
+        );
+        assert_eq!(backend.read_phys(0x100000, 4).unwrap(), b"pmem");
+        assert!(backend.read_phys(0x1000, 4).is_err());
+    }
+}
@@ -3,13 +3,14 @@
  */
 
 use clap::Parser;
-use env_logger;
-use log;
 use pmem::Cli;
 use std::process;
 
 fn main() {
     let cli = Cli::parse();
+    if let Err(err) = cli.validate() {
+        err.exit();
+    }
 
     env_logger::Builder::new()
         .filter_level(if cli.verbose {
@@ -21,6 +22,6 @@ fn main() {
 
     if let Err(err) = pmem::run(&cli) {
         eprintln!("Error: {err}");
-        process::exit(1);
+        process::exit(pmem::error::exit_code(err.as_ref()));
     }
 }
@@ -1,5 +1,9 @@
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use crate::iomem;
+use anyhow::bail;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use num_traits::{sign, Num};
+use serde::{Deserialize, Serialize};
 
 #[derive(ValueEnum, Clone, Debug, Copy)]
 pub enum AccessMode {
@@ -22,6 +26,72 @@ impl AccessMode {
     }
 }
 
+#[derive(
+    ValueEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    /// Concatenated contents of all ranges
+    Raw,
+    /// Raw image where the file offset equals the physical address
+    Padded,
+    /// Ranges with LiME headers
+    Lime,
+    /// ELF core file with one PT_LOAD segment per range
+    Elf,
+    /// AFF4 container with an image stream and a map of the ranges
+    Aff4,
+}
+
+#[derive(
+    ValueEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+    Gzip,
+}
+
+#[derive(
+    ValueEnum,
+    Clone,
+    Debug,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+    Md5,
+}
+
+#[derive(ValueEnum, Clone, Debug, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Single status line that is updated in place
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Debug, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Use the first available backend
+    Auto,
+    /// The linpmem driver
+    Linpmem,
+    /// /dev/mem (requires a kernel without STRICT_DEVMEM)
+    Devmem,
+    /// /proc/kcore (reads only, no translations or cr3)
+    Kcore,
+}
+
 fn maybe_hex<T: Num + sign::Unsigned>(s: &str) -> Result<T, String>
 where
     <T as num_traits::Num>::FromStrRadixErr: std::fmt::Display,
@@ -45,34 +115,258 @@ where
 pub enum Subcommands {
     /// Load the linpmem driver
     Insmod(InsmodCli),
+    /// Acquire all physical memory that is backed by RAM
+    Acquire(AcquireCli),
+    /// Check an image against the hashes in its manifest
+    Verify(VerifyCli),
+    /// Read memory from a process' virtual address space
+    Read(ReadCli),
+    /// List the mappings of a process by walking its page tables
+    Maps(MapsCli),
+    /// Show the information of a driver object and check it against the
+    /// running kernel
+    Modinfo(ModinfoCli),
+    /// Show whether the driver is loaded and its device file is usable
+    Status,
 }
 
 #[derive(Args, Debug)]
 pub struct InsmodCli {
-    /// Path to the linpmem.ko object file
+    /// Path to the linpmem.ko object file (default: the embedded one for the
+    /// running kernel, if any)
     pub kmod_path: Option<String>,
 
     /// Unload the driver and remove its device file
     #[arg(short, long, default_value_t = false)]
     pub rm: bool,
 
+    /// Major number of the device (default: an unused one, 0: assigned by
+    /// the kernel)
+    #[arg(long)]
+    pub major: Option<u32>,
+
+    /// Load the module even if its vermagic does not match the running
+    /// kernel
+    #[arg(short, long, default_value_t = false)]
+    pub force: bool,
+
     /// Display debug output
     #[arg(short, long, default_value_t = false)]
     pub verbose: bool,
 }
 
+impl InsmodCli {
+    /// Check that a module is given unless an embedded one can be used
+    fn validate(&self) -> Result<(), String> {
+        if !self.rm
+            && self.kmod_path.is_none()
+            && !cfg!(feature = "embedded-drivers")
+        {
+            return Err(String::from(
+                "Please specify a path to the driver object",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct AcquireCli {
+    /// Path of the output file
+    pub output: String,
+
+    /// File format of the memory image
+    #[arg(value_enum, short, long, default_value_t = ImageFormat::Raw)]
+    pub format: ImageFormat,
+
+    /// Do not write zero-filled pages of padded images, i.e., leave holes
+    #[arg(long, default_value_t = false)]
+    pub sparse: bool,
+
+    /// Compress the image while it is written
+    #[arg(value_enum, short, long)]
+    pub compress: Option<Compression>,
+
+    /// Compression level (zstd: 1-22, lz4: 0-16, gzip: 0-9)
+    #[arg(short, long)]
+    pub level: Option<u32>,
+
+    /// Additional hash algorithms for the manifest (SHA-256 is always used)
+    #[arg(value_enum, long, value_delimiter = ',')]
+    pub hash: Vec<HashAlgorithm>,
+
+    /// Split the image into numbered files of at most this size
+    #[arg(long, value_parser=maybe_hex::<u64>)]
+    pub split_size: Option<u64>,
+
+    /// Continue an interrupted acquisition using its journal
+    #[arg(long, default_value_t = false)]
+    pub resume: bool,
+
+    /// Zero-fill unreadable pages instead of failing, the zero-filled spans
+    /// are listed in the manifest and the exit code is non-zero
+    #[arg(long, default_value_t = false)]
+    pub zero_fill: bool,
+
+    /// Read the physical memory layout from a saved copy of /proc/iomem
+    #[arg(long, default_value_t = String::from(iomem::IOMEM_PATH))]
+    pub iomem: String,
+
+    /// Size of individual buffer read operations
+    #[arg(long, value_parser=maybe_hex::<u64>, default_value_t = 0x100000)]
+    pub chunk_size: u64,
+}
+
+impl AcquireCli {
+    /// Only uncompressed raw and padded images can be resumed
+    pub(crate) fn journaled(&self) -> bool {
+        matches!(self.format, ImageFormat::Raw | ImageFormat::Padded)
+            && self.compress.is_none()
+            && self.split_size.is_none()
+    }
+
+    /// Check the options that depend on each other
+    fn validate(&self) -> Result<(), String> {
+        if self.chunk_size == 0 {
+            return Err(String::from("Chunk size must not be zero"));
+        }
+        if self.sparse && self.format != ImageFormat::Padded {
+            return Err(String::from(
+                "Sparse output requires the padded format",
+            ));
+        }
+        if self.compress.is_some() && self.format == ImageFormat::Padded {
+            return Err(String::from("Padded images cannot be compressed"));
+        }
+        if self.level.is_some() && self.compress.is_none() {
+            return Err(String::from("Compression level requires --compress"));
+        }
+        if self.level.is_some() && self.format == ImageFormat::Aff4 {
+            return Err(String::from(
+                "AFF4 images do not support compression levels",
+            ));
+        }
+        if self.split_size.is_some()
+            && matches!(self.format, ImageFormat::Padded | ImageFormat::Aff4)
+        {
+            return Err(String::from("Padded and AFF4 images cannot be split"));
+        }
+        if self.resume && !self.journaled() {
+            return Err(String::from(
+                "Only uncompressed, unsplit raw and padded images can be \
+                 resumed",
+            ));
+        }
+        if let (Some(compression), Some(level)) = (self.compress, self.level) {
+            let (min, max) = compression.levels();
+            if level < min || level > max {
+                return Err(format!(
+                    "Compression level must be between {} and {}",
+                    min, max
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyCli {
+    /// Path of the manifest that was written during acquisition
+    pub manifest: String,
+
+    /// Path of the image (default: as recorded in the manifest)
+    #[arg(long)]
+    pub image: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct ReadCli {
+    /// Virtual address of the first byte
+    #[arg(long, value_parser=maybe_hex::<u64>)]
+    pub virt: u64,
+
+    /// Number of bytes to read
+    #[arg(short, long, value_parser=maybe_hex::<u64>)]
+    pub size: u64,
+
+    /// Target process (default: current process)
+    #[arg(short, long)]
+    pub pid: Option<u32>,
+
+    /// Zero-fill unmapped and unreadable pages instead of failing
+    #[arg(long, default_value_t = false)]
+    pub zero_fill: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct MapsCli {
+    /// Target process (default: current process)
+    #[arg(short, long)]
+    pub pid: Option<u32>,
+
+    /// Flag mappings that are missing from /proc/PID/maps
+    #[arg(long, default_value_t = false)]
+    pub check: bool,
+
+    /// Include pages that are only accessible from kernel mode
+    #[arg(long, default_value_t = false)]
+    pub kernel: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ModinfoCli {
+    /// Path to the linpmem.ko object file
+    pub path: String,
+}
+
 #[derive(Parser, Debug)]
 /// Stand-alone loader for the linpmem driver.
 ///
 /// This program contains only the functionality needed to load the driver.
 /// It is essentially equivalent to the `insmod` subcommand of the `pmem`
 /// binary.
-#[command(author, version)]
+#[command(name = "loader", author, version)]
 pub struct LoaderCli {
     #[command(flatten)]
     pub args: InsmodCli,
 }
 
+impl LoaderCli {
+    /// Reject arguments that clap cannot check on its own
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        self.args.validate().map_err(|msg| {
+            Self::command().error(ErrorKind::MissingRequiredArgument, msg)
+        })
+    }
+}
+
+#[derive(Debug)]
+/// Operation that was requested with the top-level options
+pub enum IOCtlCmd {
+    VtoP(u64, Option<u32>, bool),
+    Cr3(Option<u32>),
+    ReadPhys(u64, AccessMode, Option<u64>),
+}
+
+impl IOCtlCmd {
+    pub fn from_cli(cli: &Cli) -> anyhow::Result<Self> {
+        if cli.cr3 {
+            return Ok(Self::Cr3(cli.pid));
+        }
+        if let Some(virt_address) = cli.virt_address {
+            return Ok(Self::VtoP(virt_address, cli.pid, cli.show_pte));
+        }
+        if let Some(address) = cli.address {
+            return Ok(Self::ReadPhys(address, cli.mode.unwrap(), cli.size));
+        }
+
+        bail!("Invalid combination of arguments")
+    }
+}
+
 #[derive(Parser, Debug)]
 /// Command-line client for the linpmem driver.
 ///
@@ -91,6 +385,10 @@ pub struct Cli {
     #[arg(short, long, value_parser=maybe_hex::<u64>)]
     pub virt_address: Option<u64>,
 
+    /// Also decode the page-table entry that maps the virtual address
+    #[arg(long, default_value_t = false, requires("virt_address"))]
+    pub show_pte: bool,
+
     /// Size of buffer read operations
     #[arg(short, long, value_parser=maybe_hex::<u64>, required_if_eq("mode", "buffer"))]
     pub size: Option<u64>,
@@ -107,7 +405,80 @@ pub struct Cli {
     #[arg(long, default_value_t = false)]
     pub cr3: bool,
 
+    /// Source of physical memory
+    #[arg(value_enum, long, global = true, default_value_t = BackendKind::Auto)]
+    pub backend: BackendKind,
+
+    /// Report progress of long reads on stderr
+    #[arg(
+        value_enum,
+        long,
+        global = true,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    pub progress: Option<ProgressMode>,
+
     /// Display debug output
     #[arg(long, default_value_t = false)]
     pub verbose: bool,
 }
+
+impl Cli {
+    /// Reject arguments that clap cannot check on its own, e.g., because
+    /// they depend on other arguments
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        let result = match &self.subcommand {
+            Some(Subcommands::Acquire(acquire_cli)) => acquire_cli.validate(),
+            Some(Subcommands::Insmod(insmod_cli)) => insmod_cli.validate(),
+            Some(_) => Ok(()),
+            None => IOCtlCmd::from_cli(self)
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+        };
+
+        result.map_err(|msg| {
+            Self::command().error(ErrorKind::ValueValidation, msg)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_errors() {
+        let validate = |args: &[&str]| {
+            Cli::try_parse_from(args)
+                .unwrap()
+                .validate()
+                .map_err(|err| err.exit_code())
+        };
+
+        assert_eq!(validate(&["pmem", "--pid", "1"]), Err(2));
+        assert_eq!(validate(&["pmem", "--pid", "1", "--cr3"]), Ok(()));
+        assert_eq!(
+            validate(&["pmem", "acquire", "-c", "gzip", "-l", "10", "out"]),
+            Err(2)
+        );
+        assert_eq!(validate(&["pmem", "acquire", "--sparse", "out"]), Err(2));
+        assert_eq!(
+            validate(&["pmem", "acquire", "-f", "padded", "--sparse", "out"]),
+            Ok(())
+        );
+        assert_eq!(
+            validate(&["pmem", "acquire", "-c", "gzip", "--resume", "out"]),
+            Err(2)
+        );
+
+        let loader = LoaderCli::try_parse_from(["loader"]).unwrap();
+        assert_eq!(
+            loader.validate().is_ok(),
+            cfg!(feature = "embedded-drivers")
+        );
+        let loader = LoaderCli::try_parse_from(["loader", "--rm"]).unwrap();
+        assert!(loader.validate().is_ok());
+    }
+}
@@ -0,0 +1,290 @@
+//! Errors of the driver interface and the module loader
+//!
+//! Each category of errors has a distinct exit code for the command-line
+//! tools and a stable negative code for the C API. All other errors map to
+//! exit code 1 and to `-errno`, respectively.
+
+use nix::errno::Errno;
+use std::error::Error;
+use std::ffi::c_int;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// The device file does not exist, i.e., the driver is not loaded
+pub const PMEM_ERR_DEVICE_MISSING: c_int = -1001;
+/// Insufficient privileges to open the device file
+pub const PMEM_ERR_PERMISSION_DENIED: c_int = -1002;
+/// The driver rejected the physical or virtual address
+pub const PMEM_ERR_INVALID_ADDRESS: c_int = -1003;
+/// There is no process with the requested PID
+pub const PMEM_ERR_PROCESS_NOT_FOUND: c_int = -1004;
+/// The module was built for a different kernel
+pub const PMEM_ERR_MODULE_VERMAGIC: c_int = -1005;
+/// The module's signature was rejected
+pub const PMEM_ERR_MODULE_SIGNATURE: c_int = -1006;
+/// Loading modules is prohibited, e.g., by kernel lockdown
+pub const PMEM_ERR_MODULE_LOCKDOWN: c_int = -1007;
+/// The kernel rejected the module for another reason
+pub const PMEM_ERR_MODULE_REJECTED: c_int = -1008;
+/// The driver returned fewer bytes than requested
+pub const PMEM_ERR_SHORT_READ: c_int = -1009;
+
+/// Exit codes of the command-line tools, clap uses 2 for usage errors
+const EXIT_FAILURE: i32 = 1;
+const EXIT_DEVICE_MISSING: i32 = 3;
+const EXIT_PERMISSION_DENIED: i32 = 4;
+const EXIT_INVALID_ADDRESS: i32 = 5;
+const EXIT_PROCESS_NOT_FOUND: i32 = 6;
+const EXIT_MODULE_REJECTED: i32 = 7;
+const EXIT_SHORT_READ: i32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Reason why the kernel refused to load the module
+pub enum ModuleRejection {
+    /// `ENOEXEC`: the module's vermagic does not match the running kernel
+    Vermagic,
+    /// `EKEYREJECTED`: the module's signature could not be verified
+    Signature,
+    /// `EPERM`: module loading is prohibited, e.g., by kernel lockdown
+    Lockdown,
+    Other(Errno),
+}
+
+impl From<Errno> for ModuleRejection {
+    fn from(errno: Errno) -> Self {
+        match errno {
+            Errno::ENOEXEC => Self::Vermagic,
+            Errno::EKEYREJECTED => Self::Signature,
+            Errno::EPERM => Self::Lockdown,
+            errno => Self::Other(errno),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PmemError {
+    DeviceMissing(PathBuf),
+    PermissionDenied(PathBuf),
+    /// The driver rejected the address, e.g., because it is not mapped or
+    /// belongs to memory-mapped I/O
+    InvalidAddress(u64),
+    ProcessNotFound(u32),
+    ModuleRejected(ModuleRejection),
+    /// The module does not match the running kernel, thus, it was not
+    /// passed to the kernel
+    ModuleIncompatible(Vec<String>),
+    ShortRead {
+        address: u64,
+        requested: usize,
+        read: usize,
+    },
+    /// Any other error returned by the driver or the kernel
+    Os(Errno),
+    /// Failed to access a file other than the device file
+    Io(PathBuf, io::Error),
+}
+
+impl PmemError {
+    /// Classify an error that occurred when opening the device file
+    pub fn from_open(path: PathBuf, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::DeviceMissing(path),
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(path),
+            _ => Self::Io(path, err),
+        }
+    }
+
+    /// Exit code of the command-line tools
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::DeviceMissing(_) => EXIT_DEVICE_MISSING,
+            Self::PermissionDenied(_) => EXIT_PERMISSION_DENIED,
+            Self::InvalidAddress(_) => EXIT_INVALID_ADDRESS,
+            Self::ProcessNotFound(_) => EXIT_PROCESS_NOT_FOUND,
+            Self::ModuleRejected(_) | Self::ModuleIncompatible(_) => {
+                EXIT_MODULE_REJECTED
+            }
+            Self::ShortRead { .. } => EXIT_SHORT_READ,
+            Self::Os(_) | Self::Io(..) => EXIT_FAILURE,
+        }
+    }
+
+    /// Negative error code of the C API
+    pub fn code(&self) -> c_int {
+        match self {
+            Self::DeviceMissing(_) => PMEM_ERR_DEVICE_MISSING,
+            Self::PermissionDenied(_) => PMEM_ERR_PERMISSION_DENIED,
+            Self::InvalidAddress(_) => PMEM_ERR_INVALID_ADDRESS,
+            Self::ProcessNotFound(_) => PMEM_ERR_PROCESS_NOT_FOUND,
+            Self::ModuleRejected(ModuleRejection::Vermagic)
+            | Self::ModuleIncompatible(_) => PMEM_ERR_MODULE_VERMAGIC,
+            Self::ModuleRejected(ModuleRejection::Signature) => {
+                PMEM_ERR_MODULE_SIGNATURE
+            }
+            Self::ModuleRejected(ModuleRejection::Lockdown) => {
+                PMEM_ERR_MODULE_LOCKDOWN
+            }
+            Self::ModuleRejected(ModuleRejection::Other(_)) => {
+                PMEM_ERR_MODULE_REJECTED
+            }
+            Self::ShortRead { .. } => PMEM_ERR_SHORT_READ,
+            Self::Os(errno) => -(*errno as c_int),
+            Self::Io(_, err) => {
+                -err.raw_os_error().unwrap_or(Errno::EIO as c_int)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PmemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeviceMissing(path) => write!(
+                f,
+                "Device file {} does not exist, is the driver loaded?",
+                path.display()
+            ),
+            Self::PermissionDenied(path) => {
+                write!(f, "Permission denied to open {}", path.display())
+            }
+            Self::InvalidAddress(address) => write!(
+                f,
+                "Driver rejected address 0x{:016x}, it might be unmapped or MMIO",
+                address
+            ),
+            Self::ProcessNotFound(pid) => {
+                write!(f, "No process with PID {}", pid)
+            }
+            Self::ModuleRejected(ModuleRejection::Vermagic) => write!(
+                f,
+                "Module was rejected by kernel, it was built for a different kernel (vermagic mismatch)"
+            ),
+            Self::ModuleRejected(ModuleRejection::Signature) => write!(
+                f,
+                "Module was rejected by kernel, its signature is missing or not trusted"
+            ),
+            Self::ModuleRejected(ModuleRejection::Lockdown) => write!(
+                f,
+                "Module was rejected by kernel, loading modules is prohibited (lockdown?)"
+            ),
+            Self::ModuleRejected(ModuleRejection::Other(errno)) => {
+                write!(f, "Module was rejected by kernel: {}", errno.desc())
+            }
+            Self::ModuleIncompatible(problems) => write!(
+                f,
+                "Module does not match the running kernel:\n{}",
+                problems.join("\n")
+            ),
+            Self::ShortRead {
+                address,
+                requested,
+                read,
+            } => write!(
+                f,
+                "Driver read only {} of {} bytes at 0x{:016x}",
+                read, requested, address
+            ),
+            Self::Os(errno) => write!(f, "{}", errno.desc()),
+            Self::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Error for PmemError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Errno> for PmemError {
+    fn from(errno: Errno) -> Self {
+        Self::Os(errno)
+    }
+}
+
+impl From<PmemError> for io::Error {
+    fn from(err: PmemError) -> Self {
+        let kind = match &err {
+            PmemError::DeviceMissing(_) | PmemError::ProcessNotFound(_) => {
+                io::ErrorKind::NotFound
+            }
+            PmemError::PermissionDenied(_) => io::ErrorKind::PermissionDenied,
+            PmemError::InvalidAddress(_) => io::ErrorKind::InvalidInput,
+            PmemError::ShortRead { .. } => io::ErrorKind::UnexpectedEof,
+            PmemError::Io(_, err) => err.kind(),
+            PmemError::ModuleIncompatible(_) => io::ErrorKind::InvalidData,
+            PmemError::ModuleRejected(_) | PmemError::Os(_) => {
+                io::ErrorKind::Other
+            }
+        };
+
+        io::Error::new(kind, err)
+    }
+}
+
+/// Find the first `PmemError` in the chain of sources of `err`
+pub fn find<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a PmemError> {
+    let mut current = Some(err);
+
+    while let Some(err) = current {
+        if let Some(err) = err.downcast_ref::<PmemError>() {
+            return Some(err);
+        }
+        // io::Error skips the error that it wraps when reporting sources
+        if let Some(err) = err
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.get_ref())
+            .and_then(|e| e.downcast_ref::<PmemError>())
+        {
+            return Some(err);
+        }
+        current = err.source();
+    }
+
+    None
+}
+
+/// Exit code of the command-line tools for `err`
+pub fn exit_code(err: &(dyn Error + 'static)) -> i32 {
+    find(err).map(PmemError::exit_code).unwrap_or(EXIT_FAILURE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_rejection() {
+        let err = PmemError::ModuleRejected(Errno::ENOEXEC.into());
+        assert_eq!(err.code(), PMEM_ERR_MODULE_VERMAGIC);
+        assert_eq!(
+            ModuleRejection::from(Errno::EKEYREJECTED),
+            ModuleRejection::Signature
+        );
+        assert_eq!(
+            ModuleRejection::from(Errno::EPERM),
+            ModuleRejection::Lockdown
+        );
+        assert_eq!(
+            PmemError::Os(Errno::EBUSY).code(),
+            -(Errno::EBUSY as c_int)
+        );
+    }
+
+    #[test]
+    fn exit_codes_through_wrappers() {
+        let err: Box<dyn Error> = anyhow::Error::from(io::Error::from(
+            PmemError::ProcessNotFound(42),
+        ))
+        .context("Failed to query cr3")
+        .into();
+        assert_eq!(exit_code(err.as_ref()), EXIT_PROCESS_NOT_FOUND);
+
+        let err: Box<dyn Error> = "Something else".into();
+        assert_eq!(exit_code(err.as_ref()), EXIT_FAILURE);
+    }
+}
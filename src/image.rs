@@ -0,0 +1,86 @@
+//! File formats for images of physical memory
+
+pub mod aff4;
+pub mod compress;
+pub mod elf;
+pub mod lime;
+pub mod manifest;
+pub mod raw;
+pub mod split;
+
+use crate::iomem::PhysRange;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Sink for the contents of physical memory ranges
+///
+/// Ranges are written one after another, i.e., `begin_range` is called once
+/// before the contents of each range are passed to `write_chunk`.
+pub trait ImageWriter {
+    /// Start a new range
+    fn begin_range(&mut self, range: &PhysRange) -> io::Result<()>;
+
+    /// Append data to the current range
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Account for `size` bytes of the current range that are already
+    /// present in the output, e.g., when resuming an acquisition
+    fn skip(&mut self, _size: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Format does not support skipping data",
+        ))
+    }
+
+    /// Pass all buffered data on to the output
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Complete the image after the last range has been written
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Default)]
+/// Information about the host that an image was acquired from
+pub struct Metadata {
+    /// Release of the running kernel, e.g., `6.4.11-arch2-1`
+    pub kernel_release: String,
+    /// Value of cr3 of the init process
+    pub init_cr3: Option<u64>,
+    /// Start of the acquisition in seconds since the epoch
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Physical memory range that is stored at `offset` in an image file
+pub struct Segment {
+    pub range: PhysRange,
+    pub offset: u64,
+}
+
+/// Read from the segment that contains `address`
+///
+/// Reads do not cross segment boundaries, i.e., the number of bytes read may
+/// be smaller than the size of `buf`.
+pub(crate) fn read_segments<R: Read + Seek>(
+    inner: &mut R,
+    segments: &[Segment],
+    address: u64,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let Some(segment) = segments
+        .iter()
+        .find(|s| s.range.start <= address && address <= s.range.end)
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Address 0x{:016x} is not backed by a segment", address),
+        ));
+    };
+
+    let skip = address - segment.range.start;
+    let size = (buf.len() as u64).min(segment.range.size() - skip) as usize;
+
+    inner.seek(SeekFrom::Start(segment.offset + skip))?;
+    inner.read_exact(&mut buf[..size])?;
+
+    Ok(size)
+}
@@ -0,0 +1,678 @@
+//! AFF4 images as used by WinPmem, the original Linpmem and Velociraptor
+//!
+//! The contents of all ranges are stored in an `aff4:ImageStream`, i.e.,
+//! they are split into chunks that are compressed individually and grouped
+//! into segments (bevies) of the zip container. An `aff4:Map` describes
+//! where each range of physical memory is stored in the stream. All objects
+//! are described in the `information.turtle` member of the container.
+
+use super::{ImageWriter, Metadata, Segment};
+use crate::cli::Compression;
+use crate::iomem::PhysRange;
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Size of a chunk of the image stream before compression
+pub const CHUNK_SIZE: usize = 0x8000;
+/// Number of chunks in each bevy of the image stream
+pub const CHUNKS_IN_SEGMENT: usize = 0x800;
+
+const NULL_COMPRESSOR: &str = "http://aff4.org/Schema#NullCompressor";
+const DEFLATE_COMPRESSOR: &str = "https://tools.ietf.org/html/rfc1951";
+const LZ4_COMPRESSOR: &str = "https://code.google.com/p/lz4/";
+
+const UUID_PATH: &str = "/proc/sys/kernel/random/uuid";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Algorithms that can be used to compress chunks of the image stream
+pub enum ChunkCompression {
+    Stored,
+    Deflate,
+    Lz4,
+}
+
+impl TryFrom<Option<Compression>> for ChunkCompression {
+    type Error = &'static str;
+
+    fn try_from(value: Option<Compression>) -> Result<Self, Self::Error> {
+        match value {
+            None => Ok(Self::Stored),
+            Some(Compression::Gzip) => Ok(Self::Deflate),
+            Some(Compression::Lz4) => Ok(Self::Lz4),
+            Some(Compression::Zstd) => {
+                Err("AFF4 images do not support zstd compression")
+            }
+        }
+    }
+}
+
+impl ChunkCompression {
+    fn from_urn(urn: &str) -> Option<Self> {
+        [Self::Stored, Self::Deflate, Self::Lz4]
+            .into_iter()
+            .find(|compression| compression.urn() == urn)
+    }
+
+    fn urn(&self) -> &'static str {
+        match self {
+            Self::Stored => NULL_COMPRESSOR,
+            Self::Deflate => DEFLATE_COMPRESSOR,
+            Self::Lz4 => LZ4_COMPRESSOR,
+        }
+    }
+
+    /// Compress a full chunk
+    ///
+    /// Chunks that do not become smaller are stored as they are, readers
+    /// recognize them by their length.
+    fn compress<'a>(&self, chunk: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let compressed = match self {
+            Self::Stored => return Ok(Cow::Borrowed(chunk)),
+            Self::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::default(),
+                );
+                encoder.write_all(chunk)?;
+                encoder.finish()?
+            }
+            Self::Lz4 => lz4::block::compress(chunk, None, false)?,
+        };
+
+        if compressed.len() >= chunk.len() {
+            Ok(Cow::Borrowed(chunk))
+        } else {
+            Ok(Cow::Owned(compressed))
+        }
+    }
+
+    /// Decompress a chunk, chunks of full size are stored as they are
+    fn decompress(&self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        if chunk.len() == CHUNK_SIZE {
+            return Ok(chunk.to_vec());
+        }
+
+        let data = match self {
+            Self::Stored => chunk.to_vec(),
+            Self::Deflate => {
+                let mut data = Vec::with_capacity(CHUNK_SIZE);
+                flate2::read::DeflateDecoder::new(chunk)
+                    .read_to_end(&mut data)?;
+                data
+            }
+            Self::Lz4 => {
+                lz4::block::decompress(chunk, Some(CHUNK_SIZE as i32))?
+            }
+        };
+        if data.len() != CHUNK_SIZE {
+            return Err(invalid("Chunk has the wrong size"));
+        }
+
+        Ok(data)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn new_urn() -> io::Result<String> {
+    Ok(format!("aff4://{}", fs::read_to_string(UUID_PATH)?.trim()))
+}
+
+/// Name of the zip member that holds the object with the given URN
+fn member_name(urn: &str) -> String {
+    urn.replace(':', "%3A").replace('/', "%2F")
+}
+
+/// Quoted Turtle string literal with the contents of `value`
+fn turtle_string(value: &str) -> String {
+    let mut literal = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => literal += "\\\"",
+            '\\' => literal += "\\\\",
+            '\n' => literal += "\\n",
+            '\r' => literal += "\\r",
+            '\t' => literal += "\\t",
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+
+    literal
+}
+
+pub struct Aff4Writer<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    metadata: Metadata,
+    compression: ChunkCompression,
+    volume: String,
+    stream: String,
+    map: String,
+    /// Data that does not fill a complete chunk yet
+    chunk: Vec<u8>,
+    /// Number of bytes written to the zip member of the current bevy
+    bevy_size: u64,
+    /// Offsets and lengths of the chunks in the current bevy
+    bevy_index: Vec<u8>,
+    chunks_in_bevy: usize,
+    bevy_number: usize,
+    /// Number of bytes written to the image stream
+    stream_size: u64,
+    /// Physical address, size, and stream offset of all ranges
+    map_entries: Vec<(u64, u64, u64)>,
+}
+
+impl<W: Write + Seek> Aff4Writer<W> {
+    pub fn new(
+        inner: W,
+        metadata: &Metadata,
+        compression: ChunkCompression,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            zip: ZipWriter::new(inner),
+            metadata: metadata.clone(),
+            compression,
+            volume: new_urn()?,
+            stream: new_urn()?,
+            map: new_urn()?,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            bevy_size: 0,
+            bevy_index: Vec::new(),
+            chunks_in_bevy: 0,
+            bevy_number: 0,
+            stream_size: 0,
+            map_entries: Vec::new(),
+        })
+    }
+
+    /// Start a zip member, data written to `self.zip` goes into it
+    ///
+    /// Members are never larger than a bevy, i.e., they do not need zip64.
+    fn start_member(&mut self, name: &str) -> io::Result<()> {
+        self.zip.start_file(
+            name,
+            FileOptions::default()
+                .compression_method(CompressionMethod::Stored),
+        )?;
+
+        Ok(())
+    }
+
+    fn write_member(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.start_member(name)?;
+        self.zip.write_all(data)
+    }
+
+    fn bevy_name(&self) -> String {
+        format!("{}/{:08}", member_name(&self.stream), self.bevy_number)
+    }
+
+    /// Compress the current chunk and append it to the bevy's zip member
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if self.chunks_in_bevy == 0 {
+            self.start_member(&self.bevy_name())?;
+        }
+
+        // The last chunk is padded so that all chunks have the same size
+        self.chunk.resize(CHUNK_SIZE, 0);
+
+        let compressed = self.compression.compress(&self.chunk)?;
+        self.zip.write_all(&compressed)?;
+        self.bevy_index
+            .extend_from_slice(&self.bevy_size.to_le_bytes());
+        self.bevy_index
+            .extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        self.bevy_size += compressed.len() as u64;
+        self.chunk.clear();
+        self.chunks_in_bevy += 1;
+
+        if self.chunks_in_bevy == CHUNKS_IN_SEGMENT {
+            self.flush_bevy()?;
+        }
+
+        Ok(())
+    }
+
+    /// Complete the current bevy by writing its index
+    fn flush_bevy(&mut self) -> io::Result<()> {
+        if self.chunks_in_bevy == 0 {
+            return Ok(());
+        }
+
+        let name = format!("{}.index", self.bevy_name());
+        let bevy_index = std::mem::take(&mut self.bevy_index);
+
+        self.write_member(&name, &bevy_index)?;
+        self.bevy_size = 0;
+        self.chunks_in_bevy = 0;
+        self.bevy_number += 1;
+
+        Ok(())
+    }
+
+    fn map(&self) -> Vec<u8> {
+        let mut map = Vec::new();
+
+        for (address, size, offset) in &self.map_entries {
+            map.extend_from_slice(&address.to_le_bytes());
+            map.extend_from_slice(&size.to_le_bytes());
+            map.extend_from_slice(&offset.to_le_bytes());
+            // index of the image stream in the map's idx member
+            map.extend_from_slice(&0u32.to_le_bytes());
+        }
+
+        map
+    }
+
+    fn information_turtle(&self) -> String {
+        let map_size = self
+            .map_entries
+            .last()
+            .map(|(address, size, _)| address + size)
+            .unwrap_or(0);
+        let mut turtle = String::from(
+            "@prefix rdf: <http://www.w3.org/1999/02/22-rdf-syntax-ns#> .\n\
+             @prefix aff4: <http://aff4.org/Schema#> .\n\
+             @prefix xsd: <http://www.w3.org/2001/XMLSchema#> .\n\
+             @prefix memory: <http://aff4.org/Schema#memory/> .\n\
+             @prefix pmem: <https://github.com/vobst/linpmem-cli#> .\n\n",
+        );
+
+        turtle += &format!(
+            "<{volume}>\n    \
+                 a aff4:ZipVolume ;\n    \
+                 aff4:contains <{stream}>, <{map}> ;\n    \
+                 aff4:tool \"pmem {version}\" .\n\n\
+             <{stream}>\n    \
+                 a aff4:ImageStream ;\n    \
+                 aff4:chunkSize \"{chunk_size}\"^^xsd:int ;\n    \
+                 aff4:chunksInSegment \"{chunks_in_segment}\"^^xsd:int ;\n    \
+                 aff4:compressionMethod <{compression}> ;\n    \
+                 aff4:size \"{stream_size}\"^^xsd:long ;\n    \
+                 aff4:stored <{volume}> ;\n    \
+                 aff4:version \"1\"^^xsd:int .\n\n\
+             <{map}>\n    \
+                 a aff4:Image, aff4:Map ;\n    \
+                 aff4:category memory:physical ;\n    \
+                 aff4:dataStream <{stream}> ;\n    \
+                 aff4:size \"{map_size}\"^^xsd:long ;\n    \
+                 aff4:stored <{volume}> ;\n    \
+                 pmem:kernelRelease {kernel_release} ;\n    \
+                 pmem:acquisitionTime \"{time}\"^^xsd:long",
+            volume = self.volume,
+            stream = self.stream,
+            map = self.map,
+            version = env!("CARGO_PKG_VERSION"),
+            chunk_size = CHUNK_SIZE,
+            chunks_in_segment = CHUNKS_IN_SEGMENT,
+            compression = self.compression.urn(),
+            stream_size = self.stream_size,
+            map_size = map_size,
+            kernel_release = turtle_string(&self.metadata.kernel_release),
+            time = self.metadata.time,
+        );
+        if let Some(cr3) = self.metadata.init_cr3 {
+            turtle += &format!(" ;\n    pmem:initCr3 \"{}\"^^xsd:long", cr3);
+        }
+        turtle += " .\n";
+
+        turtle
+    }
+}
+
+impl<W: Write + Seek> ImageWriter for Aff4Writer<W> {
+    fn begin_range(&mut self, range: &PhysRange) -> io::Result<()> {
+        self.map_entries
+            .push((range.start, range.size(), self.stream_size));
+
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, mut data: &[u8]) -> io::Result<()> {
+        self.stream_size += data.len() as u64;
+
+        while !data.is_empty() {
+            let size = data.len().min(CHUNK_SIZE - self.chunk.len());
+
+            self.chunk.extend_from_slice(&data[..size]);
+            data = &data[size..];
+
+            if self.chunk.len() == CHUNK_SIZE {
+                self.flush_chunk()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Data is only written once a bevy is complete
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.chunk.is_empty() {
+            self.flush_chunk()?;
+        }
+        self.flush_bevy()?;
+
+        let map = member_name(&self.map);
+        let stream = self.stream.clone();
+        let volume = self.volume.clone();
+
+        self.write_member(&format!("{}/map", map), &self.map())?;
+        self.write_member(&format!("{}/idx", map), stream.as_bytes())?;
+        self.write_member(
+            "information.turtle",
+            self.information_turtle().as_bytes(),
+        )?;
+        self.write_member("container.description", volume.as_bytes())?;
+        self.write_member(
+            "version.txt",
+            format!(
+                "major=1\nminor=0\ntool=pmem {}\n",
+                env!("CARGO_PKG_VERSION")
+            )
+            .as_bytes(),
+        )?;
+
+        self.zip.set_comment(volume);
+        self.zip.finish()?.flush()
+    }
+}
+
+/// Location of a bevy's data and of its index in the container
+struct Bevy {
+    data: u64,
+    index: u64,
+    index_size: u64,
+}
+
+/// Sequential reader of the image stream of an AFF4 image
+///
+/// Only images as written by `Aff4Writer` are supported, i.e., all members
+/// must be stored without zip compression.
+pub struct Aff4Reader<R: Read + Seek> {
+    inner: R,
+    compression: ChunkCompression,
+    /// Ranges of physical memory and their offsets in the stream
+    segments: Vec<Segment>,
+    bevies: Vec<Bevy>,
+    /// Offsets and sizes of the chunks in the current bevy
+    index: Vec<(u64, u32)>,
+    /// Number of the next chunk in the stream
+    next_chunk: usize,
+    chunk: Vec<u8>,
+    /// Number of bytes of `chunk` that were already read
+    position: usize,
+}
+
+impl<R: Read + Seek> Aff4Reader<R> {
+    pub fn new(inner: R) -> io::Result<Self> {
+        let mut zip = ZipArchive::new(inner)?;
+        let read_member = |zip: &mut ZipArchive<R>, name: &str| {
+            let mut data = Vec::new();
+            zip.by_name(name)?.read_to_end(&mut data)?;
+            io::Result::Ok(data)
+        };
+
+        let map = zip
+            .file_names()
+            .find_map(|name| name.strip_suffix("/map"))
+            .ok_or_else(|| invalid("Container has no map"))?
+            .to_string();
+        let segments = read_member(&mut zip, &format!("{}/map", map))?
+            .chunks_exact(28)
+            .map(|entry| {
+                let field = |at: usize| {
+                    u64::from_le_bytes(entry[at..at + 8].try_into().unwrap())
+                };
+                let (address, size) = (field(0), field(8));
+                let end = address
+                    .checked_add(size)
+                    .filter(|_| size != 0)
+                    .ok_or_else(|| invalid("Invalid map entry"))?;
+
+                Ok(Segment {
+                    range: PhysRange::new(address, end - 1),
+                    offset: field(16),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let stream = member_name(
+            String::from_utf8_lossy(&read_member(
+                &mut zip,
+                &format!("{}/idx", map),
+            )?)
+            .trim(),
+        );
+
+        let turtle = read_member(&mut zip, "information.turtle")?;
+        let turtle = String::from_utf8_lossy(&turtle);
+        let compression = turtle
+            .split("aff4:compressionMethod <")
+            .nth(1)
+            .and_then(|rest| rest.split('>').next())
+            .and_then(ChunkCompression::from_urn)
+            .ok_or_else(|| invalid("Unknown compression method"))?;
+
+        // Bevies are numbered consecutively, each has an index
+        let mut bevies = Vec::new();
+        loop {
+            let name = format!("{}/{:08}", stream, bevies.len());
+            let Ok(data) = zip.by_name(&name).map(|f| f.data_start()) else {
+                break;
+            };
+            let index = zip.by_name(&format!("{}.index", name))?;
+
+            bevies.push(Bevy {
+                data,
+                index: index.data_start(),
+                index_size: index.size(),
+            });
+        }
+
+        Ok(Self {
+            inner: zip.into_inner(),
+            compression,
+            segments,
+            bevies,
+            index: Vec::new(),
+            next_chunk: 0,
+            chunk: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Ranges of physical memory and their offsets in the image stream
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Load the next chunk of the stream, returns false at its end
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        let bevy_number = self.next_chunk / CHUNKS_IN_SEGMENT;
+        let number = self.next_chunk % CHUNKS_IN_SEGMENT;
+        let Some(bevy) = self.bevies.get(bevy_number) else {
+            return Ok(false);
+        };
+
+        if number == 0 {
+            let mut index = vec![0u8; bevy.index_size as usize];
+            self.inner.seek(SeekFrom::Start(bevy.index))?;
+            self.inner.read_exact(&mut index)?;
+            self.index = index
+                .chunks_exact(12)
+                .map(|entry| {
+                    (
+                        u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                        u32::from_le_bytes(entry[8..].try_into().unwrap()),
+                    )
+                })
+                .collect();
+        }
+        let Some((offset, size)) = self.index.get(number) else {
+            return Ok(false);
+        };
+
+        let mut chunk = vec![0u8; *size as usize];
+        self.inner.seek(SeekFrom::Start(bevy.data + offset))?;
+        self.inner.read_exact(&mut chunk)?;
+        self.chunk = self.compression.decompress(&chunk)?;
+        self.position = 0;
+        self.next_chunk += 1;
+
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> Read for Aff4Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() && !self.next_chunk()? {
+            return Ok(0);
+        }
+
+        let size = buf.len().min(self.chunk.len() - self.position);
+        buf[..size]
+            .copy_from_slice(&self.chunk[self.position..self.position + size]);
+        self.position += size;
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Seek};
+    use zip::ZipArchive;
+
+    fn member(
+        archive: &mut ZipArchive<Cursor<Vec<u8>>>,
+        name: &str,
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let ranges = [
+            (PhysRange::new(0x1000, 0x1fff), vec![0xaa; 0x1000]),
+            (
+                PhysRange::new(0x100000, 0x108fff),
+                (0..0x9000u32).map(|i| (i % 251) as u8).collect(),
+            ),
+        ];
+        let metadata = Metadata {
+            kernel_release: String::from("6.5.0 \"custom\\build\""),
+            init_cr3: Some(0x1234000),
+            time: 1700000000,
+        };
+
+        let mut image = Cursor::new(Vec::new());
+        let mut writer =
+            Aff4Writer::new(&mut image, &metadata, ChunkCompression::Deflate)
+                .unwrap();
+        for (range, data) in &ranges {
+            writer.begin_range(range).unwrap();
+            for chunk in data.chunks(0x3000) {
+                writer.write_chunk(chunk).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        let (volume, stream, map) = (
+            writer.volume.clone(),
+            writer.stream.clone(),
+            writer.map.clone(),
+        );
+        drop(writer);
+        image.rewind().unwrap();
+
+        let mut archive = ZipArchive::new(image).unwrap();
+        assert_eq!(archive.comment(), volume.as_bytes());
+
+        // Two chunks, the second of which is padded
+        let bevy_name = format!("{}/00000000", member_name(&stream));
+        let bevy = member(&mut archive, &bevy_name);
+        let index = member(&mut archive, &format!("{}.index", bevy_name));
+        assert_eq!(index.len(), 2 * 12);
+        let mut contents = Vec::new();
+        for entry in index.chunks(12) {
+            let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let size = u32::from_le_bytes(entry[8..].try_into().unwrap());
+            let chunk = &bevy[offset as usize..][..size as usize];
+            let mut decoder = flate2::read::DeflateDecoder::new(chunk);
+            let size = decoder.read_to_end(&mut contents).unwrap();
+            assert_eq!(size, CHUNK_SIZE);
+        }
+        assert_eq!(contents[..0x1000], ranges[0].1);
+        assert_eq!(contents[0x1000..0xa000], ranges[1].1);
+        assert!(contents[0xa000..].iter().all(|b| *b == 0));
+
+        let map_member = member_name(&map);
+        let entries = member(&mut archive, &format!("{}/map", map_member));
+        let entries: Vec<u64> = entries
+            .chunks(28)
+            .flat_map(|entry| {
+                entry[..24]
+                    .chunks(8)
+                    .map(|f| u64::from_le_bytes(f.try_into().unwrap()))
+            })
+            .collect();
+        assert_eq!(entries, [0x1000, 0x1000, 0, 0x100000, 0x9000, 0x1000]);
+        assert_eq!(
+            member(&mut archive, &format!("{}/idx", map_member)),
+            stream.as_bytes()
+        );
+
+        let turtle =
+            String::from_utf8(member(&mut archive, "information.turtle"))
+                .unwrap();
+        assert!(
+            turtle.contains(&format!("<{}>\n    a aff4:Image, aff4:Map", map))
+        );
+        assert!(turtle.contains("aff4:size \"40960\"^^xsd:long"));
+        assert!(turtle.contains("aff4:size \"1085440\"^^xsd:long"));
+        assert!(turtle.contains(
+            "pmem:kernelRelease \"6.5.0 \\\"custom\\\\build\\\"\" ;"
+        ));
+        assert!(turtle.contains("pmem:initCr3 \"19087360\"^^xsd:long"));
+
+        let mut reader = Aff4Reader::new(archive.into_inner()).unwrap();
+        assert_eq!(
+            reader.segments(),
+            [
+                Segment {
+                    range: ranges[0].0,
+                    offset: 0,
+                },
+                Segment {
+                    range: ranges[1].0,
+                    offset: 0x1000,
+                },
+            ]
+        );
+        let mut stream = Vec::new();
+        reader.read_to_end(&mut stream).unwrap();
+        assert_eq!(stream, contents);
+    }
+
+    #[test]
+    fn turtle_strings() {
+        assert_eq!(turtle_string("6.5.0"), "\"6.5.0\"");
+        assert_eq!(turtle_string("a\"b\\c\nd"), "\"a\\\"b\\\\c\\nd\"");
+    }
+}
@@ -0,0 +1,178 @@
+//! Streaming compression of image files
+
+use crate::cli::Compression;
+use std::io::{self, Read, Write};
+
+impl Compression {
+    /// Range of supported compression levels
+    pub fn levels(&self) -> (u32, u32) {
+        match self {
+            Self::Zstd => (1, 22),
+            Self::Lz4 => (0, 16),
+            Self::Gzip => (0, 9),
+        }
+    }
+
+    pub fn default_level(&self) -> u32 {
+        match self {
+            Self::Zstd => 3,
+            Self::Lz4 => 0,
+            Self::Gzip => 6,
+        }
+    }
+}
+
+/// Output stage that optionally compresses everything written to it
+pub enum Encoder<W: Write> {
+    None(W),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Lz4(lz4::Encoder<W>),
+    Gzip(flate2::write::GzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(
+        inner: W,
+        compression: Option<Compression>,
+        level: Option<u32>,
+    ) -> io::Result<Self> {
+        let Some(compression) = compression else {
+            return Ok(Self::None(inner));
+        };
+
+        let level = level.unwrap_or(compression.default_level());
+        let (min, max) = compression.levels();
+        if level < min || level > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Compression level must be between {} and {}",
+                    min, max
+                ),
+            ));
+        }
+
+        Ok(match compression {
+            Compression::Zstd => Self::Zstd(zstd::stream::write::Encoder::new(
+                inner,
+                level as i32,
+            )?),
+            Compression::Lz4 => {
+                Self::Lz4(lz4::EncoderBuilder::new().level(level).build(inner)?)
+            }
+            Compression::Gzip => Self::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::new(level),
+            )),
+        })
+    }
+
+    /// Terminate the compressed stream and flush the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        let mut inner = match self {
+            Self::None(inner) => inner,
+            Self::Zstd(encoder) => encoder.finish()?,
+            Self::Lz4(encoder) => {
+                let (inner, result) = encoder.finish();
+                result?;
+                inner
+            }
+            Self::Gzip(encoder) => encoder.finish()?,
+        };
+
+        inner.flush()?;
+
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(inner) => inner.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Lz4(encoder) => encoder.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(inner) => inner.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Lz4(encoder) => encoder.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Input stage that decompresses everything read from it
+pub fn decoder<'a, R: Read + 'a>(
+    inner: R,
+    compression: Option<Compression>,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        None => Box::new(inner),
+        Some(Compression::Zstd) => {
+            Box::new(zstd::stream::read::Decoder::new(inner)?)
+        }
+        Some(Compression::Lz4) => Box::new(lz4::Decoder::new(inner)?),
+        Some(Compression::Gzip) => {
+            Box::new(flate2::read::GzDecoder::new(inner))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..0x10000u32).map(|i| (i % 251) as u8).collect();
+
+        for compression in [
+            None,
+            Some(Compression::Zstd),
+            Some(Compression::Lz4),
+            Some(Compression::Gzip),
+        ] {
+            let mut encoder =
+                Encoder::new(Vec::new(), compression, None).unwrap();
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap();
+            if compression.is_some() {
+                assert!(compressed.len() < data.len());
+            }
+
+            let mut decompressed = Vec::new();
+            decoder(compressed.as_slice(), compression)
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[test]
+    fn levels() {
+        for compression in
+            [Compression::Zstd, Compression::Lz4, Compression::Gzip]
+        {
+            let (min, max) = compression.levels();
+            assert!(
+                Encoder::new(Vec::new(), Some(compression), Some(max)).is_ok()
+            );
+            assert!(Encoder::new(Vec::new(), Some(compression), Some(max + 1))
+                .is_err());
+            if min > 0 {
+                assert!(Encoder::new(
+                    Vec::new(),
+                    Some(compression),
+                    Some(min - 1)
+                )
+                .is_err());
+            }
+        }
+    }
+}
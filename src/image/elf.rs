@@ -0,0 +1,508 @@
+//! ELF64 core files with one PT_LOAD segment per range
+//!
+//! The physical address of each range is stored in the `p_paddr` field of its
+//! program header. Metadata about the host is stored in a PT_NOTE segment that
+//! precedes the ranges.
+
+use super::{ImageWriter, Metadata, Segment};
+use crate::iomem::PhysRange;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+pub const EHDR_SIZE: u64 = 64;
+pub const PHDR_SIZE: u64 = 56;
+/// File offsets of PT_LOAD segments are aligned to this value
+pub const SEGMENT_ALIGN: u64 = 0x1000;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const EV_CURRENT: u32 = 1;
+pub const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_R: u32 = 4;
+
+/// Owner of all notes written by this module, types do not collide with the
+/// ones used by the kernel for process core dumps
+pub const NOTE_NAME: &str = "PMEM";
+/// Release of the kernel, a string
+pub const NT_PMEM_KERNEL_RELEASE: u32 = 0x100;
+/// Value of cr3 of the init process, a little-endian u64
+pub const NT_PMEM_INIT_CR3: u32 = 0x101;
+/// Start of the acquisition in seconds since the epoch, a little-endian u64
+pub const NT_PMEM_TIME: u32 = 0x102;
+
+fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+fn note(n_type: u32, desc: &[u8]) -> Vec<u8> {
+    let mut name = NOTE_NAME.as_bytes().to_vec();
+    name.push(0);
+
+    let mut note = Vec::new();
+    note.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&n_type.to_le_bytes());
+    note.extend_from_slice(&name);
+    note.resize(align_up(note.len() as u64, 4) as usize, 0);
+    note.extend_from_slice(desc);
+    note.resize(align_up(note.len() as u64, 4) as usize, 0);
+
+    note
+}
+
+fn notes(metadata: &Metadata) -> Vec<u8> {
+    let mut notes = Vec::new();
+
+    notes.extend(note(
+        NT_PMEM_KERNEL_RELEASE,
+        metadata.kernel_release.as_bytes(),
+    ));
+    if let Some(cr3) = metadata.init_cr3 {
+        notes.extend(note(NT_PMEM_INIT_CR3, &cr3.to_le_bytes()));
+    }
+    notes.extend(note(NT_PMEM_TIME, &metadata.time.to_le_bytes()));
+
+    notes
+}
+
+fn ehdr(phnum: u16) -> Vec<u8> {
+    let mut ehdr = Vec::new();
+
+    // e_ident: ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE
+    ehdr.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    ehdr.extend_from_slice(&[0u8; 8]);
+    ehdr.extend_from_slice(&ET_CORE.to_le_bytes());
+    ehdr.extend_from_slice(&EM_X86_64.to_le_bytes());
+    ehdr.extend_from_slice(&EV_CURRENT.to_le_bytes());
+    ehdr.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    ehdr.extend_from_slice(&EHDR_SIZE.to_le_bytes()); // e_phoff
+    ehdr.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    ehdr.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    ehdr.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    ehdr.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    ehdr.extend_from_slice(&phnum.to_le_bytes());
+    ehdr.extend_from_slice(&0u16.to_le_bytes()); // e_shentsize
+    ehdr.extend_from_slice(&0u16.to_le_bytes()); // e_shnum
+    ehdr.extend_from_slice(&0u16.to_le_bytes()); // e_shstrndx
+
+    ehdr
+}
+
+#[allow(clippy::too_many_arguments)]
+fn phdr(
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+) -> Vec<u8> {
+    let mut phdr = Vec::new();
+
+    phdr.extend_from_slice(&p_type.to_le_bytes());
+    phdr.extend_from_slice(&p_flags.to_le_bytes());
+    phdr.extend_from_slice(&p_offset.to_le_bytes());
+    phdr.extend_from_slice(&p_vaddr.to_le_bytes());
+    phdr.extend_from_slice(&p_paddr.to_le_bytes());
+    phdr.extend_from_slice(&p_filesz.to_le_bytes());
+    phdr.extend_from_slice(&p_memsz.to_le_bytes());
+    phdr.extend_from_slice(&p_align.to_le_bytes());
+
+    phdr
+}
+
+/// Fields of a program header that are needed to read an image
+pub(crate) struct ProgramHeader {
+    pub p_type: u32,
+    pub p_offset: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+}
+
+/// Parse the ELF header and the program headers that follow it
+pub(crate) fn program_headers<R: Read>(
+    reader: &mut R,
+) -> io::Result<Vec<ProgramHeader>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut ehdr = [0u8; EHDR_SIZE as usize];
+
+    reader.read_exact(&mut ehdr)?;
+    if &ehdr[..6] != b"\x7fELF\x02\x01" {
+        return Err(invalid("Not a little-endian ELF64 file"));
+    }
+
+    // cannot panic as the slices have the correct length
+    let phoff = u64::from_le_bytes(ehdr[32..40].try_into().unwrap());
+    let phentsize = u16::from_le_bytes(ehdr[54..56].try_into().unwrap());
+    let phnum = u16::from_le_bytes(ehdr[56..58].try_into().unwrap());
+    if phoff != EHDR_SIZE || phentsize as u64 != PHDR_SIZE {
+        return Err(invalid("Unsupported program header layout"));
+    }
+
+    let mut headers = Vec::new();
+    for _ in 0..phnum {
+        let mut phdr = [0u8; PHDR_SIZE as usize];
+        reader.read_exact(&mut phdr)?;
+
+        let field = |at: usize| {
+            u64::from_le_bytes(phdr[at..at + 8].try_into().unwrap())
+        };
+        headers.push(ProgramHeader {
+            p_type: u32::from_le_bytes(phdr[0..4].try_into().unwrap()),
+            p_offset: field(8),
+            p_paddr: field(24),
+            p_filesz: field(32),
+        });
+    }
+
+    Ok(headers)
+}
+
+/// Parse the headers at the start of an ELF file
+///
+/// Returns the PT_LOAD segments, using `p_paddr` as their start address, as
+/// well as the number of bytes consumed from `reader`. The program headers
+/// must follow the ELF header.
+pub fn parse_headers<R: Read>(
+    reader: &mut R,
+) -> io::Result<(Vec<Segment>, u64)> {
+    let headers = program_headers(reader)?;
+    let segments = headers
+        .iter()
+        .filter(|h| h.p_type == PT_LOAD && h.p_filesz != 0)
+        .map(|h| {
+            let end =
+                h.p_paddr.checked_add(h.p_filesz - 1).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Segment exceeds the physical address space",
+                    )
+                })?;
+
+            Ok(Segment {
+                range: PhysRange::new(h.p_paddr, end),
+                offset: h.p_offset,
+            })
+        })
+        .collect::<io::Result<_>>()?;
+
+    Ok((segments, EHDR_SIZE + headers.len() as u64 * PHDR_SIZE))
+}
+
+/// Read the description of the `PMEM` note of type `n_type`
+pub fn read_note<R: Read + Seek>(
+    reader: &mut R,
+    n_type: u32,
+) -> io::Result<Option<Vec<u8>>> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let headers = program_headers(reader)?;
+
+    for header in headers.iter().filter(|h| h.p_type == PT_NOTE) {
+        if header
+            .p_offset
+            .checked_add(header.p_filesz)
+            .is_none_or(|end| end > file_size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Notes exceed the file",
+            ));
+        }
+        let mut notes = vec![0u8; header.p_filesz as usize];
+        reader.seek(SeekFrom::Start(header.p_offset))?;
+        reader.read_exact(&mut notes)?;
+
+        let mut rest = &notes[..];
+        while rest.len() >= 12 {
+            let word = |at: usize| {
+                u32::from_le_bytes(rest[at..at + 4].try_into().unwrap())
+                    as usize
+            };
+            let (namesz, descsz) = (word(0), word(4));
+            let name_end = 12 + namesz;
+            let desc_start = align_up(name_end as u64, 4) as usize;
+            let desc_end = desc_start + descsz;
+            if desc_end > rest.len() {
+                break;
+            }
+
+            if word(8) as u32 == n_type
+                && rest[12..name_end] == *format!("{}\0", NOTE_NAME).as_bytes()
+            {
+                return Ok(Some(rest[desc_start..desc_end].to_vec()));
+            }
+
+            rest = &rest
+                [(align_up(desc_end as u64, 4) as usize).min(rest.len())..];
+        }
+    }
+
+    Ok(None)
+}
+
+pub struct ElfWriter<W: Write> {
+    inner: W,
+    /// Current offset in the output
+    position: u64,
+    /// Ranges and file offsets of all PT_LOAD segments
+    segments: Vec<(PhysRange, u64)>,
+    /// Index of the next segment to be written
+    next: usize,
+    /// Number of bytes that are missing from the current segment
+    remaining: u64,
+}
+
+impl<W: Write> ElfWriter<W> {
+    /// Write the ELF and program headers as well as the notes
+    ///
+    /// All ranges that will be written must be known in advance.
+    pub fn new(
+        mut inner: W,
+        ranges: &[PhysRange],
+        metadata: &Metadata,
+    ) -> io::Result<Self> {
+        let phnum = u16::try_from(ranges.len() + 1).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Too many ranges")
+        })?;
+        let notes = notes(metadata);
+        let notes_offset = EHDR_SIZE + phnum as u64 * PHDR_SIZE;
+
+        let mut segments = Vec::new();
+        let mut offset = notes_offset + notes.len() as u64;
+        for range in ranges {
+            offset = align_up(offset, SEGMENT_ALIGN);
+            segments.push((*range, offset));
+            offset += range.size();
+        }
+
+        let mut headers = ehdr(phnum);
+        headers.extend(phdr(
+            PT_NOTE,
+            0,
+            notes_offset,
+            0,
+            0,
+            notes.len() as u64,
+            0,
+            4,
+        ));
+        for (range, offset) in &segments {
+            headers.extend(phdr(
+                PT_LOAD,
+                PF_R,
+                *offset,
+                range.start,
+                range.start,
+                range.size(),
+                range.size(),
+                SEGMENT_ALIGN,
+            ));
+        }
+        headers.extend(notes);
+
+        inner.write_all(&headers)?;
+
+        Ok(Self {
+            inner,
+            position: headers.len() as u64,
+            segments,
+            next: 0,
+            remaining: 0,
+        })
+    }
+}
+
+impl<W: Write> ImageWriter for ElfWriter<W> {
+    fn begin_range(&mut self, range: &PhysRange) -> io::Result<()> {
+        let Some((expected, offset)) = self.segments.get(self.next) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Range was not announced in ELF headers",
+            ));
+        };
+        if expected != range {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected range {}, got {}", expected, range),
+            ));
+        }
+        if self.remaining != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes of the previous range are missing",
+                    self.remaining
+                ),
+            ));
+        }
+
+        let padding = offset - self.position;
+        io::copy(&mut io::repeat(0).take(padding), &mut self.inner)?;
+        self.position = *offset;
+        self.remaining = range.size();
+        self.next += 1;
+
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Data exceeds the size of the range",
+            ));
+        }
+
+        self.inner.write_all(data)?;
+        self.position += data.len() as u64;
+        self.remaining -= data.len() as u64;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Fails if not all ranges that were announced in the headers were
+    /// written completely
+    fn finish(&mut self) -> io::Result<()> {
+        let missing = self.segments[self.next..]
+            .iter()
+            .map(|(range, _)| range.size())
+            .sum::<u64>()
+            + self.remaining;
+        if missing != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes in {} of the announced ranges are missing",
+                    missing,
+                    self.segments.len() - self.next
+                        + usize::from(self.remaining != 0)
+                ),
+            ));
+        }
+
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let ranges = [
+            PhysRange::new(0x1000, 0x1fff),
+            PhysRange::new(0x100000, 0x100fff),
+        ];
+        let metadata = Metadata {
+            kernel_release: String::from("6.5.0-1-amd64"),
+            init_cr3: Some(0x1234000),
+            time: 1700000000,
+        };
+        let mut image = Vec::new();
+
+        let mut writer =
+            ElfWriter::new(&mut image, &ranges, &metadata).unwrap();
+        for (i, range) in ranges.iter().enumerate() {
+            writer.begin_range(range).unwrap();
+            writer.write_chunk(&[i as u8 + 1; 0x800]).unwrap();
+            writer.write_chunk(&[i as u8 + 1; 0x800]).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = Cursor::new(image);
+        let (segments, consumed) = parse_headers(&mut reader).unwrap();
+        assert_eq!(consumed, EHDR_SIZE + 3 * PHDR_SIZE);
+        assert_eq!(
+            segments.iter().map(|s| s.range).collect::<Vec<_>>(),
+            ranges
+        );
+        for (i, segment) in segments.iter().enumerate() {
+            assert_eq!(segment.offset % SEGMENT_ALIGN, 0);
+            let data = &reader.get_ref()[segment.offset as usize..]
+                [..segment.range.size() as usize];
+            assert!(data.iter().all(|b| *b == i as u8 + 1));
+        }
+
+        assert_eq!(
+            read_note(&mut reader, NT_PMEM_KERNEL_RELEASE).unwrap(),
+            Some(b"6.5.0-1-amd64".to_vec())
+        );
+        assert_eq!(
+            read_note(&mut reader, NT_PMEM_INIT_CR3).unwrap(),
+            Some(0x1234000u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(read_note(&mut reader, 0x1ff).unwrap(), None);
+    }
+
+    #[test]
+    fn crafted_headers() {
+        let range = PhysRange::new(0x1000, 0x1fff);
+        let mut image = Vec::new();
+        ElfWriter::new(&mut image, &[range], &Metadata::default()).unwrap();
+        let phdr = (EHDR_SIZE + PHDR_SIZE) as usize;
+
+        // Empty segments are skipped
+        image[phdr + 32..phdr + 40].copy_from_slice(&0u64.to_le_bytes());
+        let (segments, _) = parse_headers(&mut image.as_slice()).unwrap();
+        assert!(segments.is_empty());
+
+        // Notes that exceed the file
+        let notes = EHDR_SIZE as usize;
+        let mut crafted = image.clone();
+        crafted[notes + 32..notes + 40]
+            .copy_from_slice(&(u64::MAX - 0x10).to_le_bytes());
+        assert_eq!(
+            read_note(&mut Cursor::new(&crafted), NT_PMEM_INIT_CR3)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        image[phdr + 24..phdr + 32].copy_from_slice(&u64::MAX.to_le_bytes());
+        image[phdr + 32..phdr + 40].copy_from_slice(&2u64.to_le_bytes());
+        assert_eq!(
+            parse_headers(&mut image.as_slice()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn range_sizes() {
+        let ranges = [
+            PhysRange::new(0x1000, 0x1fff),
+            PhysRange::new(0x3000, 0x3fff),
+        ];
+        let mut image = Vec::new();
+        let mut writer =
+            ElfWriter::new(&mut image, &ranges, &Metadata::default()).unwrap();
+
+        assert!(writer.begin_range(&ranges[1]).is_err());
+        writer.begin_range(&ranges[0]).unwrap();
+        assert_eq!(
+            writer.write_chunk(&[0; 0x1001]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        writer.write_chunk(&[0; 0x800]).unwrap();
+        assert_eq!(
+            writer.begin_range(&ranges[1]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        // Missing bytes and ranges
+        assert!(writer.finish().is_err());
+        writer.write_chunk(&[0; 0x800]).unwrap();
+        assert!(writer.finish().is_err());
+        writer.begin_range(&ranges[1]).unwrap();
+        writer.write_chunk(&[0; 0x1000]).unwrap();
+        writer.finish().unwrap();
+    }
+}
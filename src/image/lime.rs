@@ -0,0 +1,250 @@
+//! Images in the format of the LiME loadable kernel module
+//!
+//! Each range is preceded by a 32 byte header that holds its first and last
+//! physical address.
+
+use super::{read_segments, ImageWriter, Segment};
+use crate::iomem::PhysRange;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+pub const LIME_MAGIC: u32 = 0x4c69_4d45;
+pub const LIME_VERSION: u32 = 1;
+pub const LIME_HEADER_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimeHeader {
+    pub range: PhysRange,
+}
+
+impl LimeHeader {
+    pub fn to_bytes(&self) -> [u8; LIME_HEADER_SIZE] {
+        let mut bytes = [0u8; LIME_HEADER_SIZE];
+
+        bytes[0..4].copy_from_slice(&LIME_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&LIME_VERSION.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.range.start.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.range.end.to_le_bytes());
+        // bytes[24..32] are reserved
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; LIME_HEADER_SIZE]) -> io::Result<Self> {
+        // cannot panic as the slices have the correct length
+        let magic = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let start = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let end = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+
+        if magic != LIME_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid LiME header magic",
+            ));
+        }
+        if version != LIME_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported LiME version",
+            ));
+        }
+        if end < start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "LiME range ends before it starts",
+            ));
+        }
+        // The size of the range must fit into 64 bits
+        if (end - start).checked_add(1).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "LiME range spans the whole address space",
+            ));
+        }
+
+        Ok(Self {
+            range: PhysRange::new(start, end),
+        })
+    }
+}
+
+pub struct LimeWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> LimeWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+}
+
+impl<W: Write> ImageWriter for LimeWriter<W> {
+    fn begin_range(&mut self, range: &PhysRange) -> io::Result<()> {
+        self.inner
+            .write_all(&LimeHeader { range: *range }.to_bytes())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct LimeReader<R: Read + Seek> {
+    inner: R,
+    segments: Vec<Segment>,
+}
+
+impl<R: Read + Seek> LimeReader<R> {
+    /// Parse the range headers of a LiME image
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let file_size = inner.seek(SeekFrom::End(0))?;
+        let mut segments = Vec::new();
+        let mut offset = 0;
+
+        while offset < file_size {
+            let mut bytes = [0u8; LIME_HEADER_SIZE];
+
+            inner.seek(SeekFrom::Start(offset))?;
+            inner.read_exact(&mut bytes)?;
+
+            let header = LimeHeader::from_bytes(&bytes)?;
+            let segment = Segment {
+                range: header.range,
+                offset: offset + LIME_HEADER_SIZE as u64,
+            };
+            let Some(end) = segment.offset.checked_add(segment.range.size())
+            else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "LiME range exceeds the image",
+                ));
+            };
+            if end > file_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "LiME image is truncated",
+                ));
+            }
+
+            offset = end;
+            segments.push(segment);
+        }
+
+        Ok(Self { inner, segments })
+    }
+
+    /// Physical memory ranges stored in the image
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Read physical memory starting at `address`
+    ///
+    /// Reads stop at the end of the range that contains `address`.
+    pub fn read_at(
+        &mut self,
+        address: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        read_segments(&mut self.inner, &self.segments, address, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let ranges = [
+            (PhysRange::new(0x1000, 0x1fff), 0xaa),
+            (PhysRange::new(0x100000, 0x101fff), 0x55),
+        ];
+        let mut image = Vec::new();
+
+        let mut writer = LimeWriter::new(&mut image);
+        for (range, fill) in &ranges {
+            writer.begin_range(range).unwrap();
+            writer
+                .write_chunk(&vec![*fill; range.size() as usize])
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert_eq!(image.len(), 2 * LIME_HEADER_SIZE + 0x3000);
+
+        let mut reader = LimeReader::new(Cursor::new(image)).unwrap();
+        assert_eq!(
+            reader
+                .segments()
+                .iter()
+                .map(|s| s.range)
+                .collect::<Vec<_>>(),
+            ranges.iter().map(|(r, _)| *r).collect::<Vec<_>>()
+        );
+
+        let mut buf = [0u8; 0x20];
+        assert_eq!(reader.read_at(0x1ff0, &mut buf).unwrap(), 0x10);
+        assert_eq!(buf[..0x10], [0xaa; 0x10]);
+        assert_eq!(reader.read_at(0x100800, &mut buf).unwrap(), 0x20);
+        assert_eq!(buf, [0x55; 0x20]);
+        assert!(reader.read_at(0x2000, &mut buf).is_err());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = LimeHeader {
+            range: PhysRange::new(0, 0xfff),
+        }
+        .to_bytes();
+        bytes[0] = 0;
+
+        assert!(LimeHeader::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn crafted_ranges() {
+        let header = |start: u64, end: u64| {
+            let mut bytes = LimeHeader {
+                range: PhysRange::new(0, 0xfff),
+            }
+            .to_bytes();
+            bytes[8..16].copy_from_slice(&start.to_le_bytes());
+            bytes[16..24].copy_from_slice(&end.to_le_bytes());
+            bytes
+        };
+
+        let bytes = header(0, u64::MAX);
+        assert_eq!(
+            LimeHeader::from_bytes(&bytes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            LimeReader::new(Cursor::new(bytes.to_vec()))
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // The size fits, but the end of the segment in the image does not
+        let bytes = header(1, u64::MAX);
+        LimeHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            LimeReader::new(Cursor::new(bytes.to_vec()))
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
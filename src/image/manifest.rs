@@ -0,0 +1,272 @@
+//! Cryptographic hashes of acquired memory and the manifest that records them
+//!
+//! Hashes are computed over the contents of physical memory, i.e., they do
+//! not depend on the file format or compression of the image. The hash of the
+//! whole image covers the contents of all ranges in the order in which they
+//! were acquired.
+
+use super::ImageWriter;
+use crate::cli::{Compression, HashAlgorithm, ImageFormat};
+use crate::iomem::PhysRange;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+
+/// Hex-encoded digests by algorithm
+pub type Hashes = BTreeMap<HashAlgorithm, String>;
+
+/// Compute digests with multiple algorithms at once
+///
+/// SHA-256 is always computed, the other algorithms are optional.
+pub struct Hasher {
+    sha256: Sha256,
+    blake3: Option<blake3::Hasher>,
+    md5: Option<Md5>,
+}
+
+impl Hasher {
+    pub fn new(algorithms: &[HashAlgorithm]) -> Self {
+        Self {
+            sha256: Sha256::new(),
+            blake3: algorithms
+                .contains(&HashAlgorithm::Blake3)
+                .then(blake3::Hasher::new),
+            md5: algorithms.contains(&HashAlgorithm::Md5).then(Md5::new),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        if let Some(blake3) = &mut self.blake3 {
+            blake3.update(data);
+        }
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+    }
+
+    pub fn finalize(self) -> Hashes {
+        let mut hashes = Hashes::new();
+
+        hashes.insert(
+            HashAlgorithm::Sha256,
+            format!("{:x}", self.sha256.finalize()),
+        );
+        if let Some(blake3) = self.blake3 {
+            hashes.insert(
+                HashAlgorithm::Blake3,
+                blake3.finalize().to_hex().to_string(),
+            );
+        }
+        if let Some(md5) = self.md5 {
+            hashes.insert(HashAlgorithm::Md5, format!("{:x}", md5.finalize()));
+        }
+
+        hashes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeHashes {
+    pub start: u64,
+    pub end: u64,
+    pub hashes: Hashes,
+}
+
+impl RangeHashes {
+    pub fn range(&self) -> PhysRange {
+        PhysRange::new(self.start, self.end)
+    }
+}
+
+/// Output stage that hashes all data before passing it on
+pub struct HashingWriter<'a> {
+    inner: &'a mut dyn ImageWriter,
+    algorithms: Vec<HashAlgorithm>,
+    image: Hasher,
+    current: Option<(PhysRange, Hasher)>,
+    ranges: Vec<RangeHashes>,
+}
+
+impl<'a> HashingWriter<'a> {
+    pub fn new(
+        inner: &'a mut dyn ImageWriter,
+        algorithms: &[HashAlgorithm],
+    ) -> Self {
+        Self {
+            inner,
+            algorithms: algorithms.to_vec(),
+            image: Hasher::new(algorithms),
+            current: None,
+            ranges: Vec::new(),
+        }
+    }
+
+    fn end_range(&mut self) {
+        if let Some((range, hasher)) = self.current.take() {
+            self.ranges.push(RangeHashes {
+                start: range.start,
+                end: range.end,
+                hashes: hasher.finalize(),
+            });
+        }
+    }
+
+    /// Hash data that is already present in the output
+    pub fn replay(&mut self, data: &[u8]) -> io::Result<()> {
+        self.image.update(data);
+        if let Some((_, hasher)) = &mut self.current {
+            hasher.update(data);
+        }
+
+        self.inner.skip(data.len() as u64)
+    }
+
+    /// Hashes of all ranges and of the whole image
+    pub fn into_hashes(mut self) -> (Vec<RangeHashes>, Hashes) {
+        self.end_range();
+
+        (self.ranges, self.image.finalize())
+    }
+}
+
+impl ImageWriter for HashingWriter<'_> {
+    fn begin_range(&mut self, range: &PhysRange) -> io::Result<()> {
+        self.end_range();
+        self.current = Some((*range, Hasher::new(&self.algorithms)));
+
+        self.inner.begin_range(range)
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.image.update(data);
+        if let Some((_, hasher)) = &mut self.current {
+            hasher.update(data);
+        }
+
+        self.inner.write_chunk(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.end_range();
+
+        self.inner.finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Description of an image that is stored next to it
+pub struct Manifest {
+    /// Program that created the image
+    pub tool: String,
+    /// File name of the image, relative to the manifest
+    pub image: String,
+    /// File name of the index of a split image, relative to the manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+    pub format: ImageFormat,
+    pub compression: Option<Compression>,
+    pub ranges: Vec<RangeHashes>,
+    pub hashes: Hashes,
+    /// Spans that could not be read and are zero-filled in the image
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zero_filled: Vec<PhysRange>,
+}
+
+impl Manifest {
+    /// Path of the manifest that belongs to the image at `image`
+    pub fn path_for(image: &str) -> String {
+        format!("{}.manifest.json", image)
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn store(&self, path: &str) -> io::Result<()> {
+        let mut output = BufWriter::new(File::create(path)?);
+
+        serde_json::to_writer_pretty(&mut output, self)?;
+        writeln!(output)?;
+
+        output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::raw::RawWriter;
+
+    const ABC_SHA256: &str =
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const ABC_MD5: &str = "900150983cd24fb0d6963f7d28e17f72";
+
+    #[test]
+    fn hashing_writer() {
+        let mut output = Vec::new();
+        let mut raw = RawWriter::new(&mut output);
+        let mut writer = HashingWriter::new(&mut raw, &[HashAlgorithm::Md5]);
+
+        writer.begin_range(&PhysRange::new(0x1000, 0x1002)).unwrap();
+        writer.write_chunk(b"ab").unwrap();
+        writer.write_chunk(b"c").unwrap();
+        writer.begin_range(&PhysRange::new(0x2000, 0x2002)).unwrap();
+        // Replayed data is hashed, but not written again
+        writer.replay(b"abc").unwrap();
+        writer.finish().unwrap();
+        let (ranges, image) = writer.into_hashes();
+
+        assert_eq!(ranges.len(), 2);
+        for range in &ranges {
+            assert_eq!(range.hashes[&HashAlgorithm::Sha256], ABC_SHA256);
+            assert_eq!(range.hashes[&HashAlgorithm::Md5], ABC_MD5);
+            assert!(!range.hashes.contains_key(&HashAlgorithm::Blake3));
+        }
+        assert_eq!(ranges[1].range(), PhysRange::new(0x2000, 0x2002));
+
+        let mut hasher = Hasher::new(&[HashAlgorithm::Md5]);
+        hasher.update(b"abcabc");
+        assert_eq!(image, hasher.finalize());
+        assert_eq!(output, b"abc");
+    }
+
+    #[test]
+    fn store_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory.raw.manifest.json");
+        let path = path.to_str().unwrap();
+        let mut hashes = Hashes::new();
+        hashes.insert(HashAlgorithm::Sha256, String::from(ABC_SHA256));
+        let manifest = Manifest {
+            tool: String::from("pmem"),
+            image: String::from("memory.raw"),
+            index: None,
+            format: ImageFormat::Lime,
+            compression: Some(Compression::Zstd),
+            ranges: vec![RangeHashes {
+                start: 0x1000,
+                end: 0x1fff,
+                hashes: hashes.clone(),
+            }],
+            hashes,
+            zero_filled: vec![PhysRange::new(0x1000, 0x1fff)],
+        };
+
+        manifest.store(path).unwrap();
+        let loaded = Manifest::load(path).unwrap();
+        assert_eq!(loaded.format, ImageFormat::Lime);
+        assert_eq!(loaded.compression, Some(Compression::Zstd));
+        assert_eq!(loaded.ranges, manifest.ranges);
+        assert_eq!(loaded.hashes, manifest.hashes);
+        // Images that are not split have no index
+        assert!(!std::fs::read_to_string(path).unwrap().contains("index"));
+    }
+}
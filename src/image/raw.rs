@@ -0,0 +1,215 @@
+//! Flat images without any metadata
+
+use super::ImageWriter;
+use crate::iomem::PhysRange;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Raw image that contains the concatenated contents of all ranges
+pub struct RawWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> RawWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+}
+
+impl<W: Write> ImageWriter for RawWriter<W> {
+    fn begin_range(&mut self, _range: &PhysRange) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)
+    }
+
+    /// Skipped data must precede the current position of the inner writer
+    fn skip(&mut self, _size: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Granularity at which zero-filled memory is turned into holes
+pub const SPARSE_BLOCK_SIZE: usize = 0x1000;
+
+/// Raw image where the file offset of each byte equals its physical address
+///
+/// The gaps between ranges are not written, which leaves holes in the output
+/// file on file systems that support sparse files. Optionally, zero-filled
+/// blocks inside of ranges are turned into holes as well.
+pub struct PaddedWriter<W: Write + Seek> {
+    inner: W,
+    sparse: bool,
+    /// Current offset in the output
+    position: u64,
+    /// Offset up to which data has actually been written
+    written: u64,
+}
+
+impl<W: Write + Seek> PaddedWriter<W> {
+    pub fn new(inner: W, sparse: bool) -> Self {
+        Self {
+            inner,
+            sparse,
+            position: 0,
+            written: 0,
+        }
+    }
+
+    fn write_at(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.written != self.position {
+            self.inner.seek(SeekFrom::Start(self.position))?;
+        }
+        self.inner.write_all(data)?;
+        self.position += data.len() as u64;
+        self.written = self.position;
+
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> ImageWriter for PaddedWriter<W> {
+    fn begin_range(&mut self, range: &PhysRange) -> io::Result<()> {
+        if range.start < self.position {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Range {} overlaps previous range", range),
+            ));
+        }
+        self.position = range.start;
+
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.sparse {
+            return self.write_at(data);
+        }
+
+        for block in data.chunks(SPARSE_BLOCK_SIZE) {
+            if block.iter().all(|b| *b == 0) {
+                self.position += block.len() as u64;
+            } else {
+                self.write_at(block)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Skipped data must precede the current position of the inner writer
+    fn skip(&mut self, size: u64) -> io::Result<()> {
+        self.position += size;
+        self.written = self.position;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Trailing holes do not extend the file, thus, make sure that its
+        // last byte is written.
+        if self.written < self.position {
+            self.inner.seek(SeekFrom::Start(self.position - 1))?;
+            self.inner.write_all(&[0])?;
+            self.written = self.position;
+        }
+
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Output that records the offset and size of every write
+    #[derive(Default)]
+    struct Recorder {
+        inner: Cursor<Vec<u8>>,
+        writes: Vec<(u64, usize)>,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push((self.inner.position(), buf.len()));
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Recorder {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn padded() {
+        let mut output = Recorder::default();
+        let mut writer = PaddedWriter::new(&mut output, false);
+        writer.begin_range(&PhysRange::new(0x1000, 0x1fff)).unwrap();
+        writer.write_chunk(&[1; 0x1000]).unwrap();
+        writer.begin_range(&PhysRange::new(0x3000, 0x3fff)).unwrap();
+        writer.write_chunk(&[0; 0x1000]).unwrap();
+        writer.finish().unwrap();
+
+        // The gap between the ranges is a hole
+        assert_eq!(output.writes, [(0x1000, 0x1000), (0x3000, 0x1000)]);
+        let image = output.inner.into_inner();
+        assert_eq!(image.len(), 0x4000);
+        assert!(image[0x1000..0x2000].iter().all(|b| *b == 1));
+        assert!(image[0x2000..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn sparse() {
+        let mut output = Recorder::default();
+        let mut writer = PaddedWriter::new(&mut output, true);
+        let mut data = vec![0; 3 * SPARSE_BLOCK_SIZE];
+        data[SPARSE_BLOCK_SIZE] = 1;
+        writer.begin_range(&PhysRange::new(0, 0x4fff)).unwrap();
+        writer.write_chunk(&data).unwrap();
+        writer.write_chunk(&[0; 0x2000]).unwrap();
+        writer.finish().unwrap();
+
+        // Only the non-zero block is written, and the last byte to set the
+        // size of the file
+        assert_eq!(output.writes, [(0x1000, 0x1000), (0x4fff, 1)]);
+        let image = output.inner.into_inner();
+        assert_eq!(image.len(), 0x5000);
+        assert_eq!(image[0x1000], 1);
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let mut writer = PaddedWriter::new(Cursor::new(Vec::new()), false);
+        writer.begin_range(&PhysRange::new(0x1000, 0x1fff)).unwrap();
+        writer.write_chunk(&[1; 0x1000]).unwrap();
+
+        assert_eq!(
+            writer
+                .begin_range(&PhysRange::new(0x1800, 0x2fff))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        writer.begin_range(&PhysRange::new(0x2000, 0x2fff)).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+//! Images that are split into numbered segment files of a fixed size
+//!
+//! The segments of an image at `path` are called `path.000`, `path.001`, and
+//! so on. They are listed, together with their offsets in the image, in an
+//! index file at `path.index`, which is updated whenever a segment is
+//! complete.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitSegment {
+    /// File name of the segment, relative to the index
+    pub file: String,
+    /// Offset of the segment's first byte in the image
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitIndex {
+    pub split_size: u64,
+    pub segments: Vec<SplitSegment>,
+}
+
+impl SplitIndex {
+    /// Path of the index that belongs to the image at `path`
+    pub fn path_for(path: &str) -> String {
+        format!("{}.index", path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Replace the index at `path` without leaving a partially written one
+    fn store(&self, path: &str) -> io::Result<()> {
+        let temp_path = format!("{}.tmp", path);
+        let mut output = BufWriter::new(File::create(&temp_path)?);
+
+        serde_json::to_writer_pretty(&mut output, self)?;
+        writeln!(output)?;
+        output.flush()?;
+        drop(output);
+
+        fs::rename(&temp_path, path)
+    }
+
+    /// Read the concatenated segments, which are located in `dir`
+    pub fn reader(&self, dir: &Path) -> io::Result<Box<dyn Read>> {
+        let mut reader: Box<dyn Read> = Box::new(io::empty());
+
+        for segment in &self.segments {
+            let file = File::open(dir.join(&segment.file))?;
+            reader = Box::new(reader.chain(BufReader::new(file)));
+        }
+
+        Ok(reader)
+    }
+}
+
+/// Output stage that rolls over into a new segment file every `split_size`
+/// bytes
+pub struct SplitWriter {
+    path: String,
+    index: SplitIndex,
+    current: Option<BufWriter<File>>,
+}
+
+impl SplitWriter {
+    pub fn new(path: &str, split_size: u64) -> io::Result<Self> {
+        if split_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Split size must not be zero",
+            ));
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            index: SplitIndex {
+                split_size,
+                segments: Vec::new(),
+            },
+            current: None,
+        })
+    }
+
+    fn next_segment(&mut self) -> io::Result<()> {
+        // The index lists the segments that are complete
+        if let Some(mut current) = self.current.take() {
+            current.flush()?;
+            self.index.store(&SplitIndex::path_for(&self.path))?;
+        }
+
+        let number = self.index.segments.len();
+        let path = format!("{}.{:03}", self.path, number);
+        let offset = self
+            .index
+            .segments
+            .last()
+            .map(|s| s.offset + s.size)
+            .unwrap_or(0);
+
+        self.current = Some(BufWriter::new(File::create(&path)?));
+        self.index.segments.push(SplitSegment {
+            file: Path::new(&path)
+                .file_name()
+                .expect("BUG: segment path has no file name")
+                .to_string_lossy()
+                .into_owned(),
+            offset,
+            size: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Flush the last segment and write the index
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.index.store(&SplitIndex::path_for(&self.path))
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let full = self
+            .index
+            .segments
+            .last()
+            .map(|s| s.size == self.index.split_size)
+            .unwrap_or(true);
+        if full {
+            self.next_segment()?;
+        }
+
+        // cannot panic as a segment was opened above
+        let segment = self.index.segments.last_mut().unwrap();
+        let current = self.current.as_mut().unwrap();
+        let size = buf
+            .len()
+            .min((self.index.split_size - segment.size) as usize);
+
+        let written = current.write(&buf[..size])?;
+        segment.size += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(current) => current.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory");
+        let path = path.to_str().unwrap();
+        let data: Vec<u8> = (0..0x2800).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = SplitWriter::new(path, 0x1000).unwrap();
+        writer.write_all(&data[..0x1001]).unwrap();
+
+        // The first segment is indexed as soon as it is complete
+        let index =
+            SplitIndex::load(Path::new(&SplitIndex::path_for(path))).unwrap();
+        assert_eq!(index.segments.len(), 1);
+        assert_eq!(index.segments[0].size, 0x1000);
+
+        for chunk in data[0x1001..].chunks(0x300) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let index =
+            SplitIndex::load(Path::new(&SplitIndex::path_for(path))).unwrap();
+        assert_eq!(index, writer.index);
+        let segments: Vec<_> = index
+            .segments
+            .iter()
+            .map(|s| (s.file.as_str(), s.offset, s.size))
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                ("memory.000", 0, 0x1000),
+                ("memory.001", 0x1000, 0x1000),
+                ("memory.002", 0x2000, 0x800),
+            ]
+        );
+        for segment in &index.segments {
+            let len =
+                fs::metadata(dir.path().join(&segment.file)).unwrap().len();
+            assert_eq!(len, segment.size);
+        }
+
+        let mut image = Vec::new();
+        index
+            .reader(dir.path())
+            .unwrap()
+            .read_to_end(&mut image)
+            .unwrap();
+        assert_eq!(image, data);
+    }
+
+    #[test]
+    fn exact_multiple() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory");
+        let path = path.to_str().unwrap();
+
+        let mut writer = SplitWriter::new(path, 0x1000).unwrap();
+        writer.write_all(&[1; 0x2000]).unwrap();
+        writer.finish().unwrap();
+
+        // No empty segment follows the last complete one
+        let index =
+            SplitIndex::load(Path::new(&SplitIndex::path_for(path))).unwrap();
+        assert_eq!(index.segments.len(), 2);
+        assert!(!dir.path().join("memory.002").exists());
+        assert!(SplitWriter::new(path, 0).is_err());
+    }
+}
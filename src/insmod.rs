@@ -1,86 +1,469 @@
-use crate::cli::InsmodCli;
+/// cbindgen:ignore
+pub mod compression;
+#[cfg(feature = "embedded-drivers")]
+/// cbindgen:ignore
+pub mod embedded;
+/// cbindgen:ignore
+pub mod modinfo;
+/// cbindgen:ignore
+pub mod state;
+
+use crate::cli::{InsmodCli, ModinfoCli};
+use crate::error::PmemError;
+use crate::insmod::compression::ModuleCompression;
+use crate::insmod::modinfo::{KernelMagic, ModInfo};
+use crate::insmod::state::{DeviceNode, DriverState};
 use crate::utils;
 use anyhow::{self, Context};
-use log::{debug, error};
+use log::{debug, error, warn};
+use nix::errno::Errno;
 use nix::sys::stat;
-use nix::{self, errno, kmod, unistd};
+#[cfg(feature = "embedded-drivers")]
+use nix::sys::utsname;
+use nix::{self, kmod, libc, unistd};
+use std::collections::BTreeSet;
 use std::error::Error;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::fs;
+use std::io::{self, Read, Seek};
+use std::ops::RangeInclusive;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+const PROC_DEVICES_PATH: &str = "/proc/devices";
+const SYS_DEV_CHAR_PATH: &str = "/sys/dev/char";
+/// Majors that are reserved for local use, see devices.txt in the kernel
+const LOCAL_MAJORS: [RangeInclusive<u32>; 3] = [240..=254, 60..=63, 120..=127];
+/// Flag of `finit_module` that lets the kernel decompress the module
+const MODULE_INIT_COMPRESSED_FILE: libc::c_uint = 4;
+
+/// Majors of the character devices listed in the contents of /proc/devices
+fn parse_char_devices(devices: &str) -> Vec<(u32, &str)> {
+    devices
+        .lines()
+        .skip_while(|line| !line.starts_with("Character devices:"))
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let (major, name) = line.trim().split_once(' ')?;
+            Some((major.parse().ok()?, name.trim()))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+/// Where the module is loaded from
+enum ModuleSource {
+    File(fs::File),
+    #[cfg(feature = "embedded-drivers")]
+    /// Driver object that is part of the binary
+    Memory(&'static [u8]),
+}
 
 #[derive(Debug)]
 /// Builder object that is used to perform customized module loading
 pub struct InsmodContext {
-    module: fs::File,
-    param: CString,
+    path: PathBuf,
+    module: ModuleSource,
+    compression: Option<ModuleCompression>,
+    /// Zero lets the kernel assign a major number
     major: u32,
+    /// Load the module even if it does not match the running kernel
+    force: bool,
 }
 
 impl InsmodContext {
     pub const DEV_PATH: &str = "/dev/linpmem";
     pub const DRV_NAME: &str = "linpmem";
 
-    fn from_cli(cli: &InsmodCli) -> anyhow::Result<Self> {
-        Self::build(
-            cli.kmod_path
-                .as_ref()
-                .context("Please specify a path to the driver object")?,
-        )
+    /// Errors of the loader are returned as `PmemError` to keep their exit
+    /// codes
+    fn from_cli(cli: &InsmodCli) -> Result<Self, Box<dyn Error>> {
+        let ctx = match &cli.kmod_path {
+            Some(path) => Self::build(path)?,
+            #[cfg(feature = "embedded-drivers")]
+            None => Self::embedded()?,
+            #[cfg(not(feature = "embedded-drivers"))]
+            None => {
+                return Err("Please specify a path to the driver object".into())
+            }
+        };
+
+        let ctx = ctx.with_force(cli.force);
+
+        Ok(match cli.major {
+            Some(major) => ctx.with_major(major),
+            None => ctx,
+        })
     }
 
     /// Create an InsmodContext instance that can be used to load the module
-    pub fn build(module: &str) -> anyhow::Result<Self> {
-        let major = Self::find_unused_major()
-            .context("Failed to find an unused major number")?;
+    ///
+    /// The device uses a major number that is currently unused.
+    ///
+    /// Modules that are compressed with gzip, xz, or zstd are detected by
+    /// their magic.
+    pub fn build(module: &str) -> Result<Self, PmemError> {
+        let major = Self::find_unused_major()?;
+
+        Ok(Self::open(module)?.with_major(major))
+    }
+
+    /// Open the module without choosing a major number, e.g., to inspect it
+    fn open(module: &str) -> Result<Self, PmemError> {
+        let path = PathBuf::from(module);
+        let file = fs::File::open(&path)
+            .map_err(|err| PmemError::Io(path.clone(), err))?;
+
+        let mut header = [0u8; ModuleCompression::MAGIC_SIZE];
+        let size = file
+            .read_at(&mut header, 0)
+            .map_err(|err| PmemError::Io(path.clone(), err))?;
+        let compression = ModuleCompression::detect(&header[..size]);
+        debug!("Module compression: {:?}", compression);
+
         Ok(InsmodContext {
-            module: fs::File::open(module)
-                .context(format!("Failed to open {}", module))?,
-            param: Self::build_param(major)?,
-            major,
+            path,
+            module: ModuleSource::File(file),
+            compression,
+            major: 0,
+            force: false,
         })
     }
 
-    // Todo: iterate /dev and find a chrdev major that is unused
-    fn find_unused_major() -> anyhow::Result<u32> {
-        Ok(42)
+    #[cfg(feature = "embedded-drivers")]
+    /// Create an InsmodContext instance for the embedded driver object that
+    /// was built for the running kernel
+    pub fn embedded() -> Result<Self, PmemError> {
+        let release =
+            utsname::uname()?.release().to_string_lossy().into_owned();
+        let ctx = Self::embedded_for(&release, embedded::find(&release))?;
+
+        Ok(ctx.with_major(Self::find_unused_major()?))
+    }
+
+    #[cfg(feature = "embedded-drivers")]
+    /// Embedded driver object that was found for the kernel `release`
+    ///
+    /// Objects are embedded by the name of their directory, thus, the
+    /// module's vermagic must confirm that it was built for `release`.
+    fn embedded_for(
+        release: &str,
+        driver: Option<&'static [u8]>,
+    ) -> Result<Self, PmemError> {
+        let path = PathBuf::from(format!("<embedded>/{}/linpmem.ko", release));
+        let Some(driver) = driver else {
+            return Err(PmemError::Io(
+                path,
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "No embedded driver for kernel {}, available: {}",
+                        release,
+                        embedded::releases().collect::<Vec<_>>().join(", ")
+                    ),
+                ),
+            ));
+        };
+        let compression = ModuleCompression::detect(driver);
+        debug!(
+            "Embedded module for {}, compression: {:?}",
+            release, compression
+        );
+
+        let ctx = InsmodContext {
+            path,
+            module: ModuleSource::Memory(driver),
+            compression,
+            major: 0,
+            force: false,
+        };
+        let info = ctx.modinfo()?;
+        if info.release() != Some(release) {
+            return Err(PmemError::ModuleIncompatible(vec![format!(
+                "Embedded module for kernel {} was built for kernel {}",
+                release,
+                info.release().unwrap_or("unknown")
+            )]));
+        }
+
+        Ok(ctx)
+    }
+
+    /// Use `major` for the device, zero lets the kernel assign one
+    pub fn with_major(mut self, major: u32) -> Self {
+        self.major = major;
+        self
+    }
+
+    /// Load the module even if its vermagic does not match the running
+    /// kernel
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Major number of the device, zero if it is not assigned yet
+    pub fn major(&self) -> u32 {
+        self.major
+    }
+
+    /// Majors of all registered character devices
+    fn used_majors() -> Result<BTreeSet<u32>, PmemError> {
+        let devices = fs::read_to_string(PROC_DEVICES_PATH)
+            .map_err(|err| PmemError::Io(PROC_DEVICES_PATH.into(), err))?;
+        let mut majors: BTreeSet<u32> = parse_char_devices(&devices)
+            .into_iter()
+            .map(|(major, _)| major)
+            .collect();
+
+        // Device files can exist without a registered driver, e.g., if it
+        // registered only a range of minors
+        match fs::read_dir(SYS_DEV_CHAR_PATH) {
+            Ok(entries) => majors.extend(entries.filter_map(|entry| {
+                entry
+                    .ok()?
+                    .file_name()
+                    .to_str()?
+                    .split_once(':')?
+                    .0
+                    .parse::<u32>()
+                    .ok()
+            })),
+            Err(err) => debug!("Cannot scan {}: {}", SYS_DEV_CHAR_PATH, err),
+        }
+
+        Ok(majors)
+    }
+
+    /// Find a major number for local use that is not in use
+    fn find_unused_major() -> Result<u32, PmemError> {
+        let used = Self::used_majors()?;
+
+        LOCAL_MAJORS
+            .into_iter()
+            .flatten()
+            .find(|major| !used.contains(major))
+            .ok_or_else(|| {
+                PmemError::Io(
+                    PROC_DEVICES_PATH.into(),
+                    io::Error::other(
+                        "All major numbers for local use are taken",
+                    ),
+                )
+            })
+    }
+
+    fn not_registered() -> PmemError {
+        PmemError::Io(
+            PROC_DEVICES_PATH.into(),
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not registered", Self::DRV_NAME),
+            ),
+        )
     }
 
-    fn build_param(major: u32) -> anyhow::Result<CString> {
+    fn build_param(major: u32) -> CString {
         let param = format!("major={}", major);
 
         debug!("Module load parameters: {}", param);
 
-        Ok(CString::new(param)?)
+        CString::new(param).expect("BUG: parameters contain a NUL byte")
     }
 
     /// Load the module
-    pub fn load(self) -> Result<Self, nix::errno::Errno> {
-        if let Err(err) = kmod::finit_module(
-            &self.module,
-            self.param.as_c_str(),
-            kmod::ModuleInitFlags::empty(),
-        ) {
+    ///
+    /// The errno of a failed `finit_module` is decoded into the reason why
+    /// the kernel rejected the module. If the kernel assigned the major
+    /// number, it is read back afterwards.
+    ///
+    /// If the driver is already loaded, its major is used and the module is
+    /// not loaded again.
+    pub fn load(mut self) -> Result<Self, PmemError> {
+        let state = DriverState::detect()?;
+        if let Some(module) = state.module {
+            if !module.is_live() {
+                error!("Driver is in state {}", module.state);
+                return Err(PmemError::Os(Errno::EBUSY));
+            }
+            self.major = state.major.ok_or_else(Self::not_registered)?;
+            warn!("Driver is already loaded with major {}", self.major);
+            return Ok(self);
+        }
+
+        match self.modinfo() {
+            Ok(info) => Self::check_compatibility(
+                &info,
+                &KernelMagic::running()?,
+                self.force,
+            )?,
+            Err(err) => warn!("Cannot parse module information: {}", err),
+        }
+
+        let param = Self::build_param(self.major);
+        let result = match (&self.module, self.compression) {
+            #[cfg(feature = "embedded-drivers")]
+            (ModuleSource::Memory(_), _) => {
+                kmod::init_module(&self.contents()?, &param)
+            }
+            (ModuleSource::File(file), None) => kmod::finit_module(
+                file,
+                param.as_c_str(),
+                kmod::ModuleInitFlags::empty(),
+            ),
+            (ModuleSource::File(file), Some(compression)) => {
+                match Self::finit_compressed(file, &param) {
+                    // The kernel does not know the flag, was built without
+                    // support for decompression, or for another format
+                    Err(Errno::EINVAL | Errno::EOPNOTSUPP) => {
+                        debug!(
+                            "Kernel cannot load {:?}-compressed module, \
+                         decompressing it",
+                            compression
+                        );
+                        kmod::init_module(&self.contents()?, &param)
+                    }
+                    result => result,
+                }
+            }
+        };
+        if let Err(err) = result {
             error!("Module was rejected by kernel");
-            return Err(err);
+            return Err(PmemError::ModuleRejected(err.into()));
         };
 
+        if self.major == 0 {
+            self.major =
+                DriverState::major()?.ok_or_else(Self::not_registered)?;
+            debug!("Kernel assigned major {}", self.major);
+        }
+
         Ok(self)
     }
 
-    /// Remove the module and delete the device special file
-    pub fn unload() -> Result<(), nix::errno::Errno> {
-        if let Err(err) = kmod::delete_module(
-            &CString::new(Self::DRV_NAME)
-                .expect("BUG: DRV_NAME cannot be converted to C string"),
-            kmod::DeleteModuleFlags::O_NONBLOCK,
-        ) {
-            error!("Failed to unload module");
-            return Err(err);
+    /// Parse the information in the module's `.modinfo` section
+    pub fn modinfo(&self) -> Result<ModInfo, PmemError> {
+        ModInfo::parse(&self.contents()?)
+            .map_err(|err| PmemError::Io(self.path.clone(), err))
+    }
+
+    /// Uncompressed contents of the module
+    fn contents(&self) -> Result<Vec<u8>, PmemError> {
+        let data = match &self.module {
+            ModuleSource::File(file) => {
+                let mut data = Vec::new();
+                let mut file = file;
+                file.read_to_end(&mut data)
+                    .and_then(|_| file.rewind())
+                    .map_err(|err| PmemError::Io(self.path.clone(), err))?;
+                data
+            }
+            #[cfg(feature = "embedded-drivers")]
+            ModuleSource::Memory(driver) => driver.to_vec(),
+        };
+
+        match self.compression {
+            Some(compression) => compression
+                .decompress(&data)
+                .map_err(|err| PmemError::Io(self.path.clone(), err)),
+            None => Ok(data),
+        }
+    }
+
+    /// Let the kernel decompress and load the module
+    fn finit_compressed(file: &fs::File, param: &CStr) -> Result<(), Errno> {
+        // SAFETY: the file descriptor and the parameters are valid for the
+        // duration of the call
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_finit_module,
+                file.as_raw_fd(),
+                param.as_ptr(),
+                MODULE_INIT_COMPRESSED_FILE,
+            )
         };
 
-        if let Err(err) = unistd::unlink(Self::DEV_PATH) {
-            error!("Failed to remove {}", Self::DEV_PATH);
-            return Err(err);
+        Errno::result(result).map(drop)
+    }
+
+    /// Fail with the reasons why the `kernel` would reject the module, unless
+    /// it is loaded by `force`
+    ///
+    /// Differences that the kernel does not check are only reported.
+    fn check_compatibility(
+        info: &ModInfo,
+        kernel: &KernelMagic,
+        force: bool,
+    ) -> Result<(), PmemError> {
+        for warning in info.warnings(kernel) {
+            warn!("{}", warning);
+        }
+
+        let problems = info.incompatibilities(kernel);
+        if problems.is_empty() {
+            debug!(
+                "Module {} matches the running kernel",
+                info.vermagic.as_deref().unwrap_or_default()
+            );
+        } else if force {
+            for problem in &problems {
+                warn!("{}, loading it anyway", problem);
+            }
+        } else {
+            error!(
+                "Module does not match the running kernel, use --force to \
+                 load it anyway"
+            );
+            return Err(PmemError::ModuleIncompatible(problems));
+        }
+
+        Ok(())
+    }
+
+    /// Remove the module and delete the device special file
+    ///
+    /// Refuses to unload the driver while its device is open. A device file
+    /// that is left over without the driver is removed, other files at its
+    /// path are not.
+    pub fn unload() -> Result<(), PmemError> {
+        let state = DriverState::detect()?;
+
+        match &state.module {
+            None => warn!("Driver is not loaded"),
+            Some(module) if module.refcount > 0 => {
+                error!(
+                    "Driver is still in use ({} references), close {} first",
+                    module.refcount,
+                    Self::DEV_PATH
+                );
+                return Err(PmemError::Os(Errno::EBUSY));
+            }
+            Some(_) => {
+                if let Err(err) = kmod::delete_module(
+                    &CString::new(Self::DRV_NAME).expect(
+                        "BUG: DRV_NAME cannot be converted to C string",
+                    ),
+                    kmod::DeleteModuleFlags::O_NONBLOCK,
+                ) {
+                    error!("Failed to unload module");
+                    return Err(err.into());
+                };
+            }
+        }
+
+        match state.device {
+            Some(device) if !device.character => {
+                warn!("Leaving {}, it is not a device", Self::DEV_PATH)
+            }
+            Some(_) => {
+                if let Err(err) = unistd::unlink(Self::DEV_PATH) {
+                    error!("Failed to remove {}", Self::DEV_PATH);
+                    return Err(err.into());
+                }
+            }
+            None => {}
         }
 
         Ok(())
@@ -88,65 +471,88 @@ impl InsmodContext {
 
     /// Create the device special file
     ///
-    /// Returns no error if the file already exists, i.e., it silently re-uses
-    /// an existing file.
-    pub fn mknod(self) -> Result<Self, nix::errno::Errno> {
+    /// An existing device file is re-used if it has the driver's device
+    /// number, and replaced otherwise. Files that are not character devices
+    /// are left alone.
+    pub fn mknod(self) -> Result<Self, PmemError> {
+        self.mknod_at(Self::DEV_PATH)?;
+
+        Ok(self)
+    }
+
+    fn mknod_at(&self, path: &str) -> Result<(), PmemError> {
+        match DeviceNode::stat(path)? {
+            Some(device) if device.matches(self.major) => return Ok(()),
+            Some(device) if !device.character => {
+                error!("Refusing to replace {}", path);
+                return Err(PmemError::Io(
+                    path.into(),
+                    io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "File exists and is not a character device",
+                    ),
+                ));
+            }
+            Some(device) => {
+                warn!(
+                    "Replacing {} with device number {}:{}",
+                    path, device.major, device.minor
+                );
+                unistd::unlink(path)?;
+            }
+            None => {}
+        }
+
         if let Err(err) = stat::mknod(
-            Self::DEV_PATH,
+            path,
             stat::SFlag::S_IFCHR,
             stat::Mode::S_IRUSR | stat::Mode::S_IRGRP | stat::Mode::S_IROTH,
             stat::makedev(self.major as u64, 0),
         ) {
-            if err != errno::Errno::EEXIST {
-                error!(
-                    "Failed to create device special file {}",
-                    Self::DEV_PATH
-                );
-                return Err(err);
-            }
+            error!("Failed to create device special file {}", path);
+            return Err(err.into());
         };
 
-        Ok(self)
+        Ok(())
     }
 }
 
 pub mod ffi {
     //! Public C/C++ API for loading and unloading of the driver.
+    //!
+    //! Functions return zero on success, one of the `PMEM_ERR_*` codes for
+    //! the failures that they can tell apart, and `-errno` otherwise.
 
     use super::InsmodContext;
+    use nix::errno::Errno;
     use std::ffi::{c_char, c_int, CStr};
 
     #[no_mangle]
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     /// pmem_load - load the linpmem driver
     /// @path: pointer to a string with the path to the driver object
     ///
     /// This must be called to load the linpmem driver prior to using it.
     ///
-    /// Returns zero on success, or -EXXX on failure
+    /// Returns zero on success, PMEM_ERR_MODULE_* if the kernel rejected the
+    /// module, or -EXXX on other failures
     pub extern "C" fn pmem_load(path: *const c_char) -> c_int {
         if path.is_null() {
-            return -1;
+            return -(Errno::EINVAL as c_int);
         }
 
         let path = unsafe { CStr::from_ptr(path) }.to_str();
-        let Ok(path) = path else { return -1; };
-
-        let ctx = InsmodContext::build(path);
-        let Ok(ctx) = ctx else {
-            return -1;
-        };
-
-        let ctx = ctx.load();
-        let Ok(ctx) = ctx else {
-            return ctx.unwrap_err() as c_int;
-        };
-
-        let ctx = ctx.mknod();
-        if let Err(err) = ctx {
-            return err as c_int;
+        let Ok(path) = path else {
+            return -(Errno::EINVAL as c_int);
         };
 
-        0
+        match InsmodContext::build(path)
+            .and_then(InsmodContext::load)
+            .and_then(InsmodContext::mknod)
+        {
+            Err(err) => err.code(),
+            Ok(_) => 0,
+        }
     }
 
     #[no_mangle]
@@ -154,10 +560,11 @@ pub mod ffi {
     ///
     /// This can be called to unload the linpmem driver after using it.
     ///
-    /// Returns zero on success, or -EXXX on failure
+    /// Returns zero on success, -EBUSY if the device is still open, or -EXXX
+    /// on other failures
     pub extern "C" fn pmem_unload() -> c_int {
         match InsmodContext::unload() {
-            Err(errno) => return errno as c_int,
+            Err(err) => err.code(),
             Ok(()) => 0,
         }
     }
@@ -174,3 +581,183 @@ pub fn run(cli: &InsmodCli) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// Print whether the driver is loaded and has a usable device file
+pub fn run_status() -> Result<(), Box<dyn Error>> {
+    let state = DriverState::detect()?;
+
+    match &state.module {
+        Some(module) => {
+            println!(
+                "module:   {} ({}), size {}, refcount {}",
+                InsmodContext::DRV_NAME,
+                module.state,
+                module.size,
+                module.refcount
+            );
+            if !module.holders.is_empty() {
+                println!("holders:  {}", module.holders.join(","));
+            }
+            match state.major {
+                Some(major) => println!("major:    {}", major),
+                None => println!("major:    (unknown)"),
+            }
+        }
+        None => println!("module:   not loaded"),
+    }
+    match state.device {
+        Some(device) if device.character => println!(
+            "device:   {} ({}:{})",
+            InsmodContext::DEV_PATH,
+            device.major,
+            device.minor
+        ),
+        Some(_) => {
+            println!("device:   {} (no device)", InsmodContext::DEV_PATH)
+        }
+        None => println!("device:   missing"),
+    }
+
+    let problems = state.problems();
+    if !problems.is_empty() {
+        return Err(
+            format!("Driver is not usable:\n{}", problems.join("\n")).into()
+        );
+    }
+
+    Ok(())
+}
+
+/// Print the information of a module and whether it matches the running
+/// kernel
+pub fn run_modinfo(cli: &ModinfoCli) -> Result<(), Box<dyn Error>> {
+    let info = InsmodContext::open(&cli.path)?
+        .modinfo()
+        .context(format!("Failed to parse {}", cli.path))?;
+    let unknown = String::from("(unknown)");
+
+    println!("name:       {}", info.name.as_ref().unwrap_or(&unknown));
+    println!("vermagic:   {}", info.vermagic.as_ref().unwrap_or(&unknown));
+    println!(
+        "srcversion: {}",
+        info.srcversion.as_ref().unwrap_or(&unknown)
+    );
+    println!("depends:    {}", info.depends.join(","));
+    println!(
+        "signature:  {}",
+        if info.signed { "present" } else { "none" }
+    );
+
+    let kernel = KernelMagic::running()?;
+    for warning in info.warnings(&kernel) {
+        println!("warning:    {}", warning);
+    }
+    let problems = info.incompatibilities(&kernel);
+    if !problems.is_empty() {
+        return Err(PmemError::ModuleIncompatible(problems).into());
+    }
+    println!("Module matches the running kernel {}", kernel.release);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn char_devices() {
+        let devices = "Character devices:\n  1 mem\n  4 /dev/vc/0\n\
+                       240 linpmem\n254 gpiochip\n\nBlock devices:\n\
+                       259 blkext\n";
+        assert_eq!(
+            parse_char_devices(devices),
+            [
+                (1, "mem"),
+                (4, "/dev/vc/0"),
+                (240, "linpmem"),
+                (254, "gpiochip")
+            ]
+        );
+    }
+
+    #[test]
+    fn compressed_module_file() {
+        let file = tempfile::Builder::new()
+            .suffix(".ko.zst")
+            .tempfile()
+            .unwrap();
+        let elf = modinfo::tests::module(b"name=linpmem\0", false);
+        fs::write(file.path(), zstd::encode_all(elf.as_slice(), 3).unwrap())
+            .unwrap();
+
+        let ctx = InsmodContext::build(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(ctx.compression, Some(ModuleCompression::Zstd));
+        assert_eq!(ctx.contents().unwrap(), elf);
+        assert_eq!(ctx.modinfo().unwrap().name.as_deref(), Some("linpmem"));
+    }
+
+    #[test]
+    fn incompatible_module() {
+        let info = ModInfo {
+            vermagic: Some(String::from("0.0.0-other SMP mod_unload")),
+            ..Default::default()
+        };
+
+        let kernel = KernelMagic::new("6.5.0-1-amd64", "#1 SMP");
+
+        let Err(PmemError::ModuleIncompatible(problems)) =
+            InsmodContext::check_compatibility(&info, &kernel, false)
+        else {
+            panic!("Module for another kernel is compatible");
+        };
+        assert!(problems[0].contains("0.0.0-other"));
+        InsmodContext::check_compatibility(&info, &kernel, true).unwrap();
+    }
+
+    #[cfg(feature = "embedded-drivers")]
+    #[test]
+    fn embedded_lookup() {
+        let ctx = InsmodContext::embedded_for(
+            "6.5.0-1-amd64",
+            embedded::tests::find("6.5.0-1-amd64"),
+        )
+        .unwrap();
+        assert_eq!(ctx.major(), 0);
+        assert_eq!(ctx.modinfo().unwrap().release(), Some("6.5.0-1-amd64"));
+
+        let Err(PmemError::Io(_, err)) = InsmodContext::embedded_for(
+            "6.5.0",
+            embedded::tests::find("6.5.0"),
+        ) else {
+            panic!("Missing embedded driver was found");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // The object in the directory of 6.1.0-13-amd64 is built for 6.5.0
+        let Err(PmemError::ModuleIncompatible(problems)) =
+            InsmodContext::embedded_for(
+                "6.1.0-13-amd64",
+                embedded::tests::find("6.1.0-13-amd64"),
+            )
+        else {
+            panic!("Embedded driver for another kernel was accepted");
+        };
+        assert!(problems[0].contains("6.5.0-1-amd64"));
+    }
+
+    #[test]
+    fn mknod_keeps_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("linpmem");
+        let path = path.to_str().unwrap();
+        fs::write(path, "not a device").unwrap();
+
+        let ctx = InsmodContext::open(path).unwrap().with_major(240);
+        let Err(PmemError::Io(_, err)) = ctx.mknod_at(path) else {
+            panic!("Regular file was replaced");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(path).unwrap(), "not a device");
+    }
+}
@@ -0,0 +1,115 @@
+//! Compressed kernel modules, e.g., `linpmem.ko.zst`
+
+use std::io::{self, Read};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleCompression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl ModuleCompression {
+    const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+    const XZ_MAGIC: &[u8] = b"\xfd7zXZ\x00";
+    const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+    /// Number of bytes that are needed to detect all formats
+    pub const MAGIC_SIZE: usize = 6;
+
+    /// Detect the compression of a module by the magic at its start
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(Self::GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if header.starts_with(Self::XZ_MAGIC) {
+            Some(Self::Xz)
+        } else if header.starts_with(Self::ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut module = Vec::new();
+
+        match self {
+            Self::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut module)?;
+            }
+            Self::Xz => {
+                lzma_rs::xz_decompress(
+                    &mut io::BufReader::new(data),
+                    &mut module,
+                )
+                .map_err(|err| {
+                    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+                })?;
+            }
+            Self::Zstd => {
+                zstd::stream::read::Decoder::new(data)?
+                    .read_to_end(&mut module)?;
+            }
+        }
+
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::insmod::modinfo::tests::module;
+    use crate::insmod::modinfo::ModInfo;
+    use std::io::Write;
+
+    fn compress(compression: ModuleCompression, data: &[u8]) -> Vec<u8> {
+        match compression {
+            ModuleCompression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::default(),
+                );
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            ModuleCompression::Xz => {
+                let mut compressed = Vec::new();
+                lzma_rs::xz_compress(
+                    &mut io::BufReader::new(data),
+                    &mut compressed,
+                )
+                .unwrap();
+                compressed
+            }
+            ModuleCompression::Zstd => zstd::encode_all(data, 3).unwrap(),
+        }
+    }
+
+    #[test]
+    fn compressed_modules() {
+        let elf = module(b"name=linpmem\0vermagic=6.5.0 SMP\0", true);
+        assert_eq!(ModuleCompression::detect(&elf), None);
+
+        for compression in [
+            ModuleCompression::Gzip,
+            ModuleCompression::Xz,
+            ModuleCompression::Zstd,
+        ] {
+            let compressed = compress(compression, &elf);
+            assert_eq!(
+                ModuleCompression::detect(&compressed),
+                Some(compression)
+            );
+
+            let decompressed = compression.decompress(&compressed).unwrap();
+            assert_eq!(decompressed, elf);
+            let info = ModInfo::parse(&decompressed).unwrap();
+            assert_eq!(info.name.as_deref(), Some("linpmem"));
+            assert!(info.signed);
+        }
+
+        assert!(ModuleCompression::Xz
+            .decompress(b"\xfd7zXZ\x00junk")
+            .is_err());
+    }
+}
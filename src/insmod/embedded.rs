@@ -0,0 +1,61 @@
+//! Driver objects that were embedded at build time
+//!
+//! The build script collects `<release>/linpmem.ko{,.gz,.xz,.zst}` from the
+//! directory in `PMEM_DRIVERS_DIR` (default: `drivers/`).
+
+include!(concat!(env!("OUT_DIR"), "/embedded_drivers.rs"));
+
+type Drivers = &'static [(&'static str, &'static [u8])];
+
+fn find_in(drivers: Drivers, release: &str) -> Option<&'static [u8]> {
+    drivers
+        .iter()
+        .find(|(r, _)| *r == release)
+        .map(|(_, driver)| *driver)
+}
+
+/// Driver object that was built for the kernel `release`
+pub fn find(release: &str) -> Option<&'static [u8]> {
+    find_in(DRIVERS, release)
+}
+
+/// Kernel releases that there are driver objects for
+pub fn releases() -> impl Iterator<Item = &'static str> {
+    DRIVERS.iter().map(|(release, _)| *release)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::insmod::compression::ModuleCompression;
+
+    /// Drivers in `tests/fixtures/drivers`
+    mod fixture {
+        include!(concat!(env!("OUT_DIR"), "/fixture_drivers.rs"));
+    }
+
+    /// Fixture driver object for the kernel `release`
+    pub(crate) fn find(release: &str) -> Option<&'static [u8]> {
+        find_in(fixture::DRIVERS, release)
+    }
+
+    #[test]
+    fn fixture_drivers() {
+        // Directories without a driver object are skipped
+        let releases: Vec<_> = fixture::DRIVERS
+            .iter()
+            .map(|(release, _)| *release)
+            .collect();
+        assert_eq!(releases, ["6.1.0-13-amd64", "6.5.0-1-amd64"]);
+
+        let driver = find("6.5.0-1-amd64").unwrap();
+        assert!(driver.starts_with(b"\x7fELF"));
+        let driver = find("6.1.0-13-amd64").unwrap();
+        assert_eq!(
+            ModuleCompression::detect(driver),
+            Some(ModuleCompression::Gzip)
+        );
+        assert!(find("6.6.0-1-amd64").is_none());
+        assert!(find("6.5.0").is_none());
+    }
+}
@@ -0,0 +1,419 @@
+//! Information about a kernel module from its `.modinfo` section
+//!
+//! The section holds NUL-terminated `key=value` strings. Signed modules have
+//! the signature appended to the ELF file, followed by a magic string.
+
+use crate::insmod::state;
+use nix::sys::utsname;
+use std::fs;
+use std::io;
+
+const SECTION_NAME: &[u8] = b".modinfo";
+/// Section with the CRCs of the symbols that the module uses
+const VERSIONS_SECTION_NAME: &[u8] = b"__versions";
+/// Flags in the vermagic that the kernel compares, after the release
+const VERMAGIC_FLAGS: [&str; 5] =
+    ["SMP", "preempt", "preempt_rt", "mod_unload", "modversions"];
+const SIGNATURE_MAGIC: &[u8] = b"~Module signature appended~\n";
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The `N` bytes at offset `at` of `data`
+fn bytes_at<const N: usize>(data: &[u8], at: usize) -> io::Result<[u8; N]> {
+    at.checked_add(N)
+        .and_then(|end| data.get(at..end))
+        .map(|b| b.try_into().expect("BUG: slice has the wrong size"))
+        .ok_or_else(|| invalid("Truncated ELF file"))
+}
+
+fn u16_at(data: &[u8], at: usize) -> io::Result<u16> {
+    bytes_at(data, at).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], at: usize) -> io::Result<u32> {
+    bytes_at(data, at).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], at: usize) -> io::Result<u64> {
+    bytes_at(data, at).map(u64::from_le_bytes)
+}
+
+/// Contents of the section called `name` of the ELF64 file in `data`
+fn section<'a>(data: &'a [u8], name: &[u8]) -> io::Result<Option<&'a [u8]>> {
+    if data.len() < EHDR_SIZE || !data.starts_with(b"\x7fELF\x02\x01") {
+        return Err(invalid("Not a little-endian ELF64 file"));
+    }
+
+    let shoff = u64_at(data, 0x28)? as usize;
+    let shnum = u16_at(data, 0x3c)? as usize;
+    let shstrndx = u16_at(data, 0x3e)? as usize;
+    let header = |index: usize| -> io::Result<(u32, &'a [u8])> {
+        let shdr: [u8; SHDR_SIZE] = index
+            .checked_mul(SHDR_SIZE)
+            .and_then(|offset| shoff.checked_add(offset))
+            .ok_or_else(|| invalid("Section header exceeds file"))
+            .and_then(|at| bytes_at(data, at))?;
+        let offset = u64_at(&shdr, 0x18)? as usize;
+        let size = u64_at(&shdr, 0x20)? as usize;
+        let contents = offset
+            .checked_add(size)
+            .and_then(|end| data.get(offset..end))
+            .ok_or_else(|| invalid("Section exceeds file"))?;
+
+        Ok((u32_at(&shdr, 0)?, contents))
+    };
+
+    let (_, names) = header(shstrndx)?;
+    for index in 0..shnum {
+        let (name_offset, contents) = header(index)?;
+        let section_name = names
+            .get(name_offset as usize..)
+            .and_then(|n| n.split(|b| *b == 0).next())
+            .unwrap_or_default();
+        if section_name == name {
+            return Ok(Some(contents));
+        }
+    }
+
+    Ok(None)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModInfo {
+    pub name: Option<String>,
+    pub vermagic: Option<String>,
+    pub srcversion: Option<String>,
+    pub depends: Vec<String>,
+    pub signed: bool,
+    /// Whether the module has the CRCs of the symbols that it uses
+    pub modversions: bool,
+}
+
+impl ModInfo {
+    /// Parse the uncompressed module in `data`
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let modinfo = section(data, SECTION_NAME)?
+            .ok_or_else(|| invalid("Module has no .modinfo section"))?;
+        let mut info = Self {
+            signed: data.ends_with(SIGNATURE_MAGIC),
+            modversions: section(data, VERSIONS_SECTION_NAME)?.is_some(),
+            ..Default::default()
+        };
+
+        for entry in modinfo.split(|b| *b == 0) {
+            let entry = String::from_utf8_lossy(entry);
+            let Some((key, value)) = entry.split_once('=') else {
+                continue;
+            };
+
+            match key {
+                "name" => info.name = Some(value.to_string()),
+                "vermagic" => info.vermagic = Some(value.trim().to_string()),
+                "srcversion" => info.srcversion = Some(value.to_string()),
+                "depends" => {
+                    info.depends = value
+                        .split(',')
+                        .filter(|d| !d.is_empty())
+                        .map(String::from)
+                        .collect()
+                }
+                _ => {}
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Kernel release that the module was built for
+    pub fn release(&self) -> Option<&str> {
+        self.vermagic.as_deref()?.split_whitespace().next()
+    }
+
+    /// Whether the vermagic contains `flag`
+    fn has_flag(&self, flag: &str) -> bool {
+        self.vermagic
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .skip(1)
+            .any(|f| f == flag)
+    }
+
+    /// Reasons why the `kernel` would reject the module
+    ///
+    /// Like the kernel, the release is only compared if the module has no
+    /// CRCs of its symbols. Flags of the kernel that are unknown are not
+    /// compared.
+    pub fn incompatibilities(&self, kernel: &KernelMagic) -> Vec<String> {
+        if self.vermagic.is_none() {
+            return vec![String::from("Module has no vermagic")];
+        }
+        let mut problems = Vec::new();
+
+        if !self.modversions && self.release() != Some(&kernel.release) {
+            problems.push(format!(
+                "Module was built for kernel {}, but {} is running",
+                self.release().unwrap_or_default(),
+                kernel.release
+            ));
+        }
+        for flag in VERMAGIC_FLAGS {
+            let Some(running) = kernel.flag(flag) else {
+                continue;
+            };
+            let built = self.has_flag(flag);
+            if built != running {
+                problems.push(format!(
+                    "Module was built {} {}, but the running kernel {}",
+                    if built { "with" } else { "without" },
+                    flag,
+                    if running { "has it" } else { "does not" }
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Differences to the `kernel` that it does not reject the module for
+    pub fn warnings(&self, kernel: &KernelMagic) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.modversions
+            && self.vermagic.is_some()
+            && self.release() != Some(&kernel.release)
+        {
+            warnings.push(format!(
+                "Module was built for kernel {}, but {} is running, only \
+                 the CRCs of its symbols are checked",
+                self.release().unwrap_or_default(),
+                kernel.release
+            ));
+        }
+
+        warnings
+    }
+}
+
+/// Parts of a kernel's vermagic, as far as they are known
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KernelMagic {
+    /// Kernel release, i.e., `uname -r`
+    pub release: String,
+    pub smp: bool,
+    pub preempt: bool,
+    pub preempt_rt: bool,
+    /// Whether modules can be unloaded, unknown if none is loaded
+    pub mod_unload: Option<bool>,
+    /// Whether symbols are versioned, unknown without the kernel's
+    /// configuration
+    pub modversions: Option<bool>,
+}
+
+impl KernelMagic {
+    /// Magic of the kernel with the release and version `uname -r` and
+    /// `uname -v`
+    pub fn new(release: &str, version: &str) -> Self {
+        let flags: Vec<_> = version.split_whitespace().collect();
+        let preempt_rt = flags.contains(&"PREEMPT_RT");
+
+        Self {
+            release: release.to_string(),
+            smp: flags.contains(&"SMP"),
+            preempt: !preempt_rt
+                && flags
+                    .iter()
+                    .any(|f| *f == "PREEMPT" || *f == "PREEMPT_DYNAMIC"),
+            preempt_rt,
+            mod_unload: None,
+            modversions: None,
+        }
+    }
+
+    /// Magic of the running kernel
+    ///
+    /// Whether modules can be unloaded is told from /proc/modules, and
+    /// whether symbols are versioned from the kernel's configuration in
+    /// /boot.
+    pub fn running() -> nix::Result<Self> {
+        let uts = utsname::uname()?;
+        let mut kernel = Self::new(
+            &uts.release().to_string_lossy(),
+            &uts.version().to_string_lossy(),
+        );
+
+        kernel.mod_unload = state::module_unloading();
+        kernel.modversions =
+            fs::read_to_string(format!("/boot/config-{}", kernel.release))
+                .ok()
+                .map(|config| {
+                    config.lines().any(|l| l == "CONFIG_MODVERSIONS=y")
+                });
+
+        Ok(kernel)
+    }
+
+    /// Whether the kernel's vermagic contains `flag`, if it is known
+    fn flag(&self, flag: &str) -> Option<bool> {
+        match flag {
+            "SMP" => Some(self.smp),
+            "preempt" => Some(self.preempt),
+            "preempt_rt" => Some(self.preempt_rt),
+            "mod_unload" => self.mod_unload,
+            "modversions" => self.modversions,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// ELF64 file with a `.modinfo` section of the given contents
+    pub(crate) fn module(modinfo: &[u8], signed: bool) -> Vec<u8> {
+        module_with_sections(&[(SECTION_NAME, modinfo)], signed)
+    }
+
+    /// ELF64 file with the given sections
+    fn module_with_sections(
+        sections: &[(&[u8], &[u8])],
+        signed: bool,
+    ) -> Vec<u8> {
+        let mut names = vec![0u8];
+        let mut data = vec![0u8; EHDR_SIZE];
+        data[..6].copy_from_slice(b"\x7fELF\x02\x01");
+
+        let mut headers = Vec::new();
+        for (name, contents) in sections
+            .iter()
+            .chain([&(b".shstrtab".as_slice(), b"".as_slice())])
+        {
+            headers.push((names.len(), data.len(), contents.len()));
+            names.extend_from_slice(name);
+            names.push(0);
+            data.extend_from_slice(contents);
+        }
+        // The names are the contents of the last section
+        let names_header = headers.last_mut().unwrap();
+        names_header.1 = data.len();
+        names_header.2 = names.len();
+        data.extend_from_slice(&names);
+
+        let shoff = data.len();
+        data.extend_from_slice(&[0u8; SHDR_SIZE]);
+        for (name, offset, size) in &headers {
+            let mut shdr = [0u8; SHDR_SIZE];
+            shdr[..4].copy_from_slice(&(*name as u32).to_le_bytes());
+            shdr[0x18..0x20].copy_from_slice(&(*offset as u64).to_le_bytes());
+            shdr[0x20..0x28].copy_from_slice(&(*size as u64).to_le_bytes());
+            data.extend_from_slice(&shdr);
+        }
+        data[0x28..0x30].copy_from_slice(&(shoff as u64).to_le_bytes());
+        data[0x3c..0x3e]
+            .copy_from_slice(&(headers.len() as u16 + 1).to_le_bytes());
+        data[0x3e..0x40].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+
+        if signed {
+            data.extend_from_slice(b"signature");
+            data.extend_from_slice(SIGNATURE_MAGIC);
+        }
+
+        data
+    }
+
+    #[test]
+    fn parse() {
+        let data = module(
+            b"license=GPL\0depends=\0srcversion=ABC123\0name=linpmem\0\
+              vermagic=6.5.0-1-amd64 SMP preempt mod_unload modversions \0",
+            true,
+        );
+
+        let info = ModInfo::parse(&data).unwrap();
+        assert_eq!(info.name.as_deref(), Some("linpmem"));
+        assert_eq!(info.srcversion.as_deref(), Some("ABC123"));
+        assert!(info.depends.is_empty());
+        assert!(info.signed);
+        assert!(!info.modversions);
+        assert_eq!(info.release(), Some("6.5.0-1-amd64"));
+
+        let info = ModInfo::parse(&module(b"depends=a,b\0", false)).unwrap();
+        assert_eq!(info.depends, ["a", "b"]);
+        assert!(!info.signed);
+        let kernel = KernelMagic::new("6.5.0", "#1 SMP");
+        assert_eq!(info.incompatibilities(&kernel), ["Module has no vermagic"]);
+
+        assert!(ModInfo::parse(b"not an ELF file").is_err());
+    }
+
+    #[test]
+    fn compatibility() {
+        let vermagic = b"vermagic=6.5.0-1-amd64 SMP preempt mod_unload \0";
+        let info = ModInfo::parse(&module(vermagic, false)).unwrap();
+        let version = "#1 SMP PREEMPT_DYNAMIC Debian 6.5.3-1";
+        let mut kernel = KernelMagic::new("6.5.0-1-amd64", version);
+        assert!(kernel.smp && kernel.preempt && !kernel.preempt_rt);
+        assert!(info.incompatibilities(&kernel).is_empty());
+
+        // Flags are compared once they are known
+        kernel.mod_unload = Some(true);
+        kernel.modversions = Some(true);
+        assert_eq!(
+            info.incompatibilities(&kernel),
+            [
+                "Module was built without modversions, but the running kernel \
+              has it"
+            ]
+        );
+        kernel.modversions = None;
+
+        let rt = KernelMagic::new("6.5.0-1-amd64", "#1 SMP PREEMPT_RT");
+        assert!(rt.preempt_rt && !rt.preempt);
+        assert_eq!(info.incompatibilities(&rt).len(), 2);
+        kernel.release = String::from("6.6.0");
+        assert_eq!(info.incompatibilities(&kernel).len(), 1);
+        assert!(info.warnings(&kernel).is_empty());
+
+        // With the CRCs of its symbols, the release of the module does not
+        // matter to the kernel
+        let info = ModInfo::parse(&module_with_sections(
+            &[(SECTION_NAME, vermagic), (VERSIONS_SECTION_NAME, b"crcs")],
+            false,
+        ))
+        .unwrap();
+        assert!(info.modversions);
+        assert!(info.incompatibilities(&kernel).is_empty());
+        assert_eq!(info.warnings(&kernel).len(), 1);
+    }
+
+    #[test]
+    fn crafted_offsets() {
+        let data = module(b"name=linpmem\0", false);
+
+        // Section headers at the end of the address space
+        let mut crafted = data.clone();
+        crafted[0x28..0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(ModInfo::parse(&crafted).is_err());
+        let mut crafted = data.clone();
+        crafted[0x28..0x30].copy_from_slice(&(u64::MAX - 0x10).to_le_bytes());
+        assert!(ModInfo::parse(&crafted).is_err());
+
+        // Section that exceeds the file
+        let mut crafted = data.clone();
+        let shoff = u64_at(&data, 0x28).unwrap() as usize;
+        crafted[shoff + SHDR_SIZE + 0x20..shoff + SHDR_SIZE + 0x28]
+            .copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(ModInfo::parse(&crafted).is_err());
+
+        assert_eq!(
+            bytes_at::<2>(&data, usize::MAX).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
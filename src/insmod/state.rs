@@ -0,0 +1,251 @@
+//! State of the driver, i.e., whether it is loaded and has a device file
+
+use crate::error::PmemError;
+use crate::insmod::{parse_char_devices, InsmodContext, PROC_DEVICES_PATH};
+use log::debug;
+use nix::sys::stat;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+const PROC_MODULES_PATH: &str = "/proc/modules";
+const SYS_MODULE_PATH: &str = "/sys/module";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Entry of a module in /proc/modules
+pub struct LoadedModule {
+    pub size: u64,
+    /// Number of references, e.g., open device files or dependent modules
+    pub refcount: u32,
+    /// Modules that depend on this one
+    pub holders: Vec<String>,
+    /// `Live`, `Loading`, or `Unloading`
+    pub state: String,
+}
+
+impl LoadedModule {
+    pub fn is_live(&self) -> bool {
+        self.state == "Live"
+    }
+}
+
+/// Entry of the module `name` in the contents of /proc/modules
+fn parse_proc_modules(modules: &str, name: &str) -> Option<LoadedModule> {
+    modules.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != name {
+            return None;
+        }
+
+        Some(LoadedModule {
+            size: fields.next()?.parse().ok()?,
+            // Kernels without module unloading print a dash
+            refcount: fields.next()?.parse().unwrap_or_default(),
+            holders: fields
+                .next()?
+                .split(',')
+                .filter(|holder| !holder.is_empty() && *holder != "-")
+                .map(String::from)
+                .collect(),
+            state: fields.next()?.to_string(),
+        })
+    })
+}
+
+/// Whether the kernel supports unloading modules, unknown if none is loaded
+pub(crate) fn module_unloading() -> Option<bool> {
+    let modules = fs::read_to_string(PROC_MODULES_PATH).ok()?;
+
+    parse_module_unloading(&modules)
+}
+
+fn parse_module_unloading(modules: &str) -> Option<bool> {
+    let refcount = modules.lines().next()?.split_whitespace().nth(2)?;
+
+    // Kernels without module unloading print a dash
+    Some(refcount != "-")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Device special file of the driver
+pub struct DeviceNode {
+    pub character: bool,
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl DeviceNode {
+    /// Device special file at `path`, if it exists
+    pub fn stat(path: &str) -> Result<Option<Self>, PmemError> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(err) => return Err(PmemError::Io(path.into(), err)),
+        };
+
+        Ok(Some(Self {
+            character: metadata.file_type().is_char_device(),
+            major: stat::major(metadata.rdev()) as u32,
+            minor: stat::minor(metadata.rdev()) as u32,
+        }))
+    }
+
+    /// Whether this is the device of the driver with the given major
+    pub fn matches(&self, major: u32) -> bool {
+        self.character && self.major == major && self.minor == 0
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DriverState {
+    /// Module if it is loaded
+    pub module: Option<LoadedModule>,
+    /// Major of the character device that the driver registered
+    pub major: Option<u32>,
+    pub device: Option<DeviceNode>,
+}
+
+impl DriverState {
+    /// Inspect /proc/modules, /sys/module, and the device special file
+    pub fn detect() -> Result<Self, PmemError> {
+        let modules = match fs::read_to_string(PROC_MODULES_PATH) {
+            Ok(modules) => modules,
+            // Kernel without support for loadable modules
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("Cannot read {}: {}", PROC_MODULES_PATH, err);
+                String::new()
+            }
+            Err(err) => {
+                return Err(PmemError::Io(PROC_MODULES_PATH.into(), err))
+            }
+        };
+        let module = parse_proc_modules(&modules, InsmodContext::DRV_NAME);
+        let major = match module {
+            Some(_) => Self::major()?,
+            None => None,
+        };
+
+        Ok(Self {
+            module,
+            major,
+            device: DeviceNode::stat(InsmodContext::DEV_PATH)?,
+        })
+    }
+
+    /// Major of the loaded driver
+    ///
+    /// The module parameter is zero if the kernel assigned the major, which
+    /// is then looked up in /proc/devices.
+    pub(crate) fn major() -> Result<Option<u32>, PmemError> {
+        let param = format!(
+            "{}/{}/parameters/major",
+            SYS_MODULE_PATH,
+            InsmodContext::DRV_NAME
+        );
+        match fs::read_to_string(&param).map(|p| p.trim().parse::<u32>()) {
+            Ok(Ok(major)) if major != 0 => return Ok(Some(major)),
+            Ok(_) => {}
+            Err(err) => debug!("Cannot read {}: {}", param, err),
+        }
+
+        let devices = fs::read_to_string(PROC_DEVICES_PATH)
+            .map_err(|err| PmemError::Io(PROC_DEVICES_PATH.into(), err))?;
+
+        Ok(parse_char_devices(&devices)
+            .into_iter()
+            .find(|(_, name)| *name == InsmodContext::DRV_NAME)
+            .map(|(major, _)| major))
+    }
+
+    /// Reasons why the driver cannot be used in its current state
+    pub fn problems(&self) -> Vec<String> {
+        let dev_path = InsmodContext::DEV_PATH;
+        let mut problems = Vec::new();
+
+        let Some(module) = &self.module else {
+            if self.device.is_some() {
+                problems.push(format!(
+                    "{} exists, but the driver is not loaded",
+                    dev_path
+                ));
+            }
+            return problems;
+        };
+
+        if !module.is_live() {
+            problems.push(format!("Driver is in state {}", module.state));
+        }
+        match (self.major, self.device) {
+            (None, _) => problems
+                .push(String::from("Driver has not registered its device")),
+            (Some(_), None) => {
+                problems.push(format!("{} is missing", dev_path))
+            }
+            (Some(_), Some(device)) if !device.character => {
+                problems.push(format!("{} is not a character device", dev_path))
+            }
+            (Some(major), Some(device)) if !device.matches(major) => problems
+                .push(format!(
+                    "{} has the device number {}:{}, but the driver uses \
+                     major {}",
+                    dev_path, device.major, device.minor, major
+                )),
+            _ => {}
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn driver_state() {
+        let modules = "kvm_intel 380928 0 - Live 0x0000000000000000\n\
+                       linpmem 16384 1 - Live 0x0000000000000000 (OE)\n\
+                       kvm 1142784 1 kvm_intel, Live 0x0000000000000000\n";
+        let module = parse_proc_modules(modules, "linpmem").unwrap();
+        assert_eq!(module.size, 16384);
+        assert_eq!(module.refcount, 1);
+        assert!(module.holders.is_empty());
+        assert!(module.is_live());
+        assert_eq!(
+            parse_proc_modules(modules, "kvm").unwrap().holders,
+            ["kvm_intel"]
+        );
+        assert_eq!(parse_proc_modules(modules, "kvm_amd"), None);
+        assert_eq!(parse_module_unloading(modules), Some(true));
+        assert_eq!(
+            parse_module_unloading("linpmem 16384 - - Live 0x0\n"),
+            Some(false)
+        );
+        assert_eq!(parse_module_unloading(""), None);
+
+        let device = DeviceNode {
+            character: true,
+            major: 240,
+            minor: 0,
+        };
+        let mut state = DriverState {
+            module: Some(module),
+            major: Some(240),
+            device: Some(device),
+        };
+        assert!(state.problems().is_empty());
+
+        state.major = Some(241);
+        assert_eq!(state.problems().len(), 1);
+
+        state.device = None;
+        assert_eq!(state.problems(), ["/dev/linpmem is missing"]);
+
+        state.module = None;
+        assert!(state.problems().is_empty());
+        state.device = Some(device);
+        assert_eq!(state.problems().len(), 1);
+    }
+}
@@ -1,79 +1,370 @@
-use crate::cli::{AccessMode, Cli};
+//! Interface to the linpmem driver
+
+use crate::backend::Backend;
+use crate::cli::AccessMode;
+use crate::error::PmemError;
 use crate::insmod::InsmodContext;
-use anyhow::{bail, Context};
-use std::error::Error;
+use crate::paging::{PageTableWalker, PagingMode, PteInfo, CPUINFO_PATH};
+use nix::errno::Errno;
+use std::env;
 use std::fs::File;
-use std::io::{self, Write};
-use std::os::fd::AsRawFd;
+use std::io;
+use std::path::{Path, PathBuf};
 
 mod ffi;
 
-#[derive(Debug)]
-pub enum IOCtlCmd {
-    VtoP(u64, Option<u32>),
-    Cr3(Option<u32>),
-    ReadPhys(u64, AccessMode, Option<u64>),
-}
+use ffi::Transport;
 
-impl IOCtlCmd {
-    pub fn from_cli(cli: &Cli) -> anyhow::Result<Self> {
-        if cli.cr3 {
-            return Ok(Self::Cr3(cli.pid));
-        }
-        if let Some(virt_address) = cli.virt_address {
-            return Ok(Self::VtoP(virt_address, cli.pid));
-        }
-        if let Some(address) = cli.address {
-            return Ok(Self::ReadPhys(address, cli.mode.unwrap(), cli.size));
-        }
+/// Environment variable with the path of the device file, e.g., of a mock
+pub const DEVICE_ENV: &str = "PMEM_DEVICE";
 
-        bail!("Invalid combination of arguments")
+/// Open handle to the driver's device file
+pub struct Driver {
+    transport: Box<dyn Transport>,
+}
+
+/// The driver signals unmapped or otherwise inaccessible addresses with
+/// `EINVAL`
+fn address_error(errno: Errno, address: u64) -> PmemError {
+    match errno {
+        Errno::EINVAL => PmemError::InvalidAddress(address),
+        errno => PmemError::Os(errno),
     }
 }
 
-pub struct Driver {
-    handle: File,
+/// Recover the driver's error from an error of a `Backend` method, other
+/// errors concern the translation of `address`
+fn backend_error(err: io::Error, address: u64) -> PmemError {
+    match err.into_inner().map(|err| err.downcast::<PmemError>()) {
+        Some(Ok(err)) => *err,
+        _ => PmemError::InvalidAddress(address),
+    }
 }
 
 impl Driver {
-    pub fn build() -> anyhow::Result<Self> {
-        let handle = File::open(InsmodContext::DEV_PATH)
-            .context("Cannot open device file. Does it exist and do I have the permission to open it?")?;
-
-        Ok(Self { handle })
+    /// Open the device file at the path in `PMEM_DEVICE`, or at its default
+    /// path
+    pub fn build() -> Result<Self, PmemError> {
+        match env::var_os(DEVICE_ENV) {
+            Some(path) => Self::open(path),
+            None => Self::open(InsmodContext::DEV_PATH),
+        }
     }
 
-    pub fn cr3(&self, pid: Option<u32>) -> Result<(), Box<dyn Error>> {
-        let cr3_pa = ffi::cr3(self.handle.as_raw_fd(), pid)?;
+    /// Open the device file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PmemError> {
+        let path = path.as_ref();
 
-        println!("0x{:016x}", cr3_pa);
+        let file = File::open(path)
+            .map_err(|err| PmemError::from_open(path.to_path_buf(), err))?;
 
-        Ok(())
+        Ok(Self {
+            transport: Box::new(file),
+        })
     }
 
+    /// Value of cr3 of process `pid` (default: current process)
+    pub fn cr3(&self, pid: Option<u32>) -> Result<u64, PmemError> {
+        ffi::cr3(self.transport.as_ref(), pid).map_err(|errno| {
+            match (pid, errno) {
+                (Some(pid), Errno::ESRCH | Errno::EINVAL) => {
+                    PmemError::ProcessNotFound(pid)
+                }
+                (_, errno) => PmemError::Os(errno),
+            }
+        })
+    }
+
+    /// Translate `virt_address` in the address space of process `pid`
+    /// (default: current process)
     pub fn v_to_p(
         &self,
         virt_address: u64,
         pid: Option<u32>,
-    ) -> Result<(), Box<dyn Error>> {
-        let phys_address =
-            ffi::v_to_p(self.handle.as_raw_fd(), virt_address, pid)?;
+    ) -> Result<u64, PmemError> {
+        Ok(self.v_to_p_pte(virt_address, pid)?.0)
+    }
+
+    /// Translate `virt_address` like `v_to_p`
+    ///
+    /// Returns the physical address and the kernel virtual address of the
+    /// entry that maps the page.
+    pub fn v_to_p_pte(
+        &self,
+        virt_address: u64,
+        pid: Option<u32>,
+    ) -> Result<(u64, u64), PmemError> {
+        // zero makes the driver use the page tables of the current process
+        let cr3 = match pid {
+            Some(pid) => self.cr3(Some(pid))?,
+            None => 0,
+        };
+
+        self.v_to_p_cr3(virt_address, cr3)
+    }
+
+    /// Translate `virt_address` with the page tables at `cr3`
+    ///
+    /// Returns the physical address and the kernel virtual address of the
+    /// entry that maps the page.
+    pub fn v_to_p_cr3(
+        &self,
+        virt_address: u64,
+        cr3: u64,
+    ) -> Result<(u64, u64), PmemError> {
+        ffi::v_to_p(self.transport.as_ref(), virt_address, cr3)
+            .map_err(|errno| address_error(errno, virt_address))
+    }
+
+    /// Read and decode the entry that maps `virt_address` in the address
+    /// space of process `pid` (default: current process)
+    ///
+    /// The size of the page follows from the level of the entry, thus, the
+    /// page tables are walked in physical memory. The result includes the
+    /// translation of `virt_address`, which must agree with the driver's.
+    pub fn pte(
+        &self,
+        virt_address: u64,
+        pid: Option<u32>,
+    ) -> Result<PteInfo, PmemError> {
+        let mode = PagingMode::detect()
+            .map_err(|err| PmemError::Io(PathBuf::from(CPUINFO_PATH), err))?;
+
+        self.pte_with_mode(virt_address, pid, mode)
+    }
+
+    fn pte_with_mode(
+        &self,
+        virt_address: u64,
+        pid: Option<u32>,
+        mode: PagingMode,
+    ) -> Result<PteInfo, PmemError> {
+        let cr3 = self.cr3(pid)?;
+        let (phys_address, address) = self.v_to_p_cr3(virt_address, cr3)?;
 
-        println!("0x{:016x}", phys_address);
+        let translation = PageTableWalker::new(self, cr3, mode)
+            .translate(virt_address)
+            .map_err(|err| backend_error(err, virt_address))?
+            .ok_or(PmemError::InvalidAddress(virt_address))?;
+        if translation.phys_address != phys_address {
+            return Err(PmemError::Io(
+                PathBuf::from(CPUINFO_PATH),
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Driver translates 0x{:016x} to 0x{:016x}, but the \
+                         page tables walked with {:?} paging to 0x{:016x}",
+                        virt_address,
+                        phys_address,
+                        mode,
+                        translation.phys_address
+                    ),
+                ),
+            ));
+        }
 
-        Ok(())
+        Ok(PteInfo {
+            address,
+            phys_address: translation.pte_address,
+            pte: translation.pte,
+            page_size: translation.page_size,
+            translated: translation.phys_address,
+        })
     }
 
+    /// Read exactly `size` bytes starting at physical `address`
+    ///
+    /// Fails with `PmemError::ShortRead` if the driver returns fewer bytes.
     pub fn read_phys(
+        &self,
+        address: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, PmemError> {
+        let mut buf = vec![0u8; size as usize];
+        let read = self.read_phys_into(address, &mut buf)?;
+
+        if read < buf.len() {
+            return Err(PmemError::ShortRead {
+                address,
+                requested: buf.len(),
+                read,
+            });
+        }
+
+        Ok(buf)
+    }
+
+    /// Read into `buf` starting at physical `address`
+    ///
+    /// Returns the number of bytes read, which may be smaller than the size
+    /// of `buf`.
+    pub fn read_phys_into(
+        &self,
+        address: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, PmemError> {
+        ffi::read_buffer(self.transport.as_ref(), address, buf)
+            .map_err(|errno| address_error(errno, address))
+    }
+
+    /// Read a single value with the width given by `mode`
+    ///
+    /// Fails with `EINVAL` for `AccessMode::Buffer`.
+    pub fn read_value(
+        &self,
+        address: u64,
+        mode: AccessMode,
+    ) -> Result<u64, PmemError> {
+        if mode.size().is_none() {
+            return Err(PmemError::Os(Errno::EINVAL));
+        }
+
+        ffi::read_value(self.transport.as_ref(), address, mode)
+            .map_err(|errno| address_error(errno, address))
+    }
+}
+
+impl Backend for Driver {
+    fn read_phys(&self, address: u64, size: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; size as usize];
+        let read = self.read_phys_into(address, &mut buf)?;
+        buf.truncate(read);
+
+        Ok(buf)
+    }
+
+    fn read_value(
         &self,
         address: u64,
         mode: AccessMode,
-        size: Option<u64>,
-    ) -> Result<(), Box<dyn Error>> {
-        let mem = ffi::read_phys(self.handle.as_raw_fd(), address, mode, size)?;
+    ) -> io::Result<Vec<u8>> {
+        let value = Driver::read_value(self, address, mode)?;
+
+        // cannot panic as reading a value succeeded
+        Ok(value.to_le_bytes()[..mode.size().unwrap()].to_vec())
+    }
+
+    fn v_to_p(&self, virt_address: u64, pid: Option<u32>) -> io::Result<u64> {
+        Ok(Driver::v_to_p(self, virt_address, pid)?)
+    }
+
+    fn v_to_p_cr3(&self, virt_address: u64, cr3: u64) -> io::Result<u64> {
+        Ok(Driver::v_to_p_cr3(self, virt_address, cr3)?.0)
+    }
+
+    fn cr3(&self, pid: Option<u32>) -> io::Result<u64> {
+        Ok(Driver::cr3(self, pid)?)
+    }
+
+    fn pte(&self, virt_address: u64, pid: Option<u32>) -> io::Result<PteInfo> {
+        Ok(Driver::pte(self, virt_address, pid)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffi::tests::FakeDriver;
+
+    fn driver(reported_size: Option<u64>) -> Driver {
+        Driver {
+            transport: Box::new(FakeDriver {
+                memory: (0..0x2000).map(|a| (a >> 4) as u8).collect(),
+                reported_size,
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn read_phys() {
+        let driver = driver(None);
+        let mut buf = [0u8; 0x20];
+        assert_eq!(driver.read_phys_into(0x100, &mut buf).unwrap(), 0x20);
+        assert_eq!(buf[0x1f], 0x11);
+        assert_eq!(driver.read_phys(0x1ff0, 0x10).unwrap(), [0xff; 0x10]);
+        assert!(matches!(
+            driver.read_phys(0x1ff0, 0x20),
+            Err(PmemError::InvalidAddress(0x1ff0))
+        ));
+
+        let driver = self::driver(Some(8));
+        assert_eq!(driver.read_phys_into(0x100, &mut buf).unwrap(), 8);
+        assert!(matches!(
+            driver.read_phys(0x100, 0x20),
+            Err(PmemError::ShortRead {
+                address: 0x100,
+                requested: 0x20,
+                read: 8
+            })
+        ));
+        // Backends return what was read
+        assert_eq!(Backend::read_phys(&driver, 0x100, 0x20).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn read_value() {
+        let driver = driver(None);
+        assert_eq!(driver.read_value(0x120, AccessMode::Word).unwrap(), 0x1212);
+        assert_eq!(
+            Backend::read_value(&driver, 0x120, AccessMode::Dword).unwrap(),
+            [0x12; 4]
+        );
+        assert!(matches!(
+            driver.read_value(0x120, AccessMode::Buffer),
+            Err(PmemError::Os(Errno::EINVAL))
+        ));
+        assert!(matches!(
+            driver.read_value(0x2000, AccessMode::Byte),
+            Err(PmemError::InvalidAddress(0x2000))
+        ));
+    }
+
+    #[test]
+    fn pte() {
+        // 4K page at 0x40_0000 with the tables at 0x1000 (PML4), 0x2000
+        // (PDPT), 0x3000 (PD), and 0x4000 (PT)
+        let mut memory = vec![0u8; 0x6000];
+        for (address, value) in [
+            (0x1000, 0x2003u64),
+            (0x2000, 0x3003),
+            (0x3000 + 2 * 8, 0x4003),
+            (0x4000, 0x5003),
+        ] {
+            memory[address..address + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        let driver = |phys_address: u64| Driver {
+            transport: Box::new(FakeDriver {
+                memory: memory.clone(),
+                cr3: 0x1000,
+                translation: Some((phys_address, 0xffff_8880_0000_4000)),
+                ..Default::default()
+            }),
+        };
 
-        io::stdout().write_all(mem.as_slice())?;
+        let info = driver(0x5123)
+            .pte_with_mode(0x40_0123, None, PagingMode::FourLevel)
+            .unwrap();
+        assert_eq!(info.address, 0xffff_8880_0000_4000);
+        assert_eq!(info.phys_address, 0x4000);
+        assert_eq!(info.translated, 0x5123);
 
-        Ok(())
+        // The driver and the walker disagree, e.g., about the paging mode
+        assert!(matches!(
+            driver(0x5123).pte_with_mode(
+                0x40_0123,
+                None,
+                PagingMode::FiveLevel
+            ),
+            Err(PmemError::InvalidAddress(0x40_0123))
+        ));
+        assert!(matches!(
+            driver(0x9123).pte_with_mode(
+                0x40_0123,
+                None,
+                PagingMode::FourLevel
+            ),
+            Err(PmemError::Io(..))
+        ));
     }
 }
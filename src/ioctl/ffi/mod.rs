@@ -1,12 +1,13 @@
 //! Safe bindings for all IOCTLs supported by the driver
 
 /// cbindgen:ignore
-#[allow(non_upper_case_globals, unused, non_camel_case_types)]
-mod bindings;
+#[allow(non_upper_case_globals, unused, non_camel_case_types, non_snake_case)]
+pub(crate) mod bindings;
 
 use crate::cli::AccessMode;
 use nix::ioctl_readwrite;
-use std::os::fd;
+use std::fs::File;
+use std::os::fd::AsRawFd;
 use std::ptr;
 
 ioctl_readwrite!(
@@ -18,6 +19,44 @@ ioctl_readwrite!(
 ioctl_readwrite!(unsafe_v_to_p, b'a', b'b', bindings::LINPMEM_VTOP_INFO);
 ioctl_readwrite!(unsafe_cr3, b'a', b'c', bindings::LINPMEM_CR3_INFO);
 
+/// Channel that the requests reach the driver through, i.e., its device
+/// file
+pub(crate) trait Transport: Send + Sync {
+    fn read_write_phys(
+        &self,
+        data: &mut bindings::LINPMEM_DATA_TRANSFER,
+    ) -> nix::Result<()>;
+
+    fn v_to_p(&self, data: &mut bindings::LINPMEM_VTOP_INFO)
+        -> nix::Result<()>;
+
+    fn cr3(&self, data: &mut bindings::LINPMEM_CR3_INFO) -> nix::Result<()>;
+}
+
+impl Transport for File {
+    fn read_write_phys(
+        &self,
+        data: &mut bindings::LINPMEM_DATA_TRANSFER,
+    ) -> nix::Result<()> {
+        // SAFETY: the request is valid for the duration of the call, and
+        // its read buffer, if any, for its size
+        unsafe { unsafe_read_write_pyhs(self.as_raw_fd(), data) }.map(drop)
+    }
+
+    fn v_to_p(
+        &self,
+        data: &mut bindings::LINPMEM_VTOP_INFO,
+    ) -> nix::Result<()> {
+        // SAFETY: the request is valid for the duration of the call
+        unsafe { unsafe_v_to_p(self.as_raw_fd(), data) }.map(drop)
+    }
+
+    fn cr3(&self, data: &mut bindings::LINPMEM_CR3_INFO) -> nix::Result<()> {
+        // SAFETY: the request is valid for the duration of the call
+        unsafe { unsafe_cr3(self.as_raw_fd(), data) }.map(drop)
+    }
+}
+
 impl From<AccessMode> for u8 {
     fn from(value: AccessMode) -> u8 {
         match value {
@@ -40,76 +79,212 @@ impl From<AccessMode> for u8 {
     }
 }
 
-pub fn read_phys(
-    fd: fd::RawFd,
+/// Read a single value, `mode` must not be `AccessMode::Buffer`
+pub fn read_value(
+    transport: &dyn Transport,
     address: u64,
     mode: AccessMode,
-    size: Option<u64>,
-) -> Result<Vec<u8>, nix::errno::Errno> {
-    // cannot panic on 64 bit as usize == u64
-    let mut mem: Vec<u8> =
-        Vec::with_capacity(size.unwrap_or(0).try_into().unwrap());
+) -> Result<u64, nix::errno::Errno> {
+    if mode.size().is_none() {
+        return Err(nix::errno::Errno::EINVAL);
+    }
+
     let mut data_transfer = bindings::LINPMEM_DATA_TRANSFER {
         phys_address: address,
         out_value: 0,
-        readbuffer: match mode {
-            AccessMode::Buffer => mem.as_mut_ptr() as *mut std::ffi::c_void,
-            _ => ptr::null_mut(),
-        },
-        readbuffer_size: size.unwrap_or(0),
+        readbuffer: ptr::null_mut(),
+        readbuffer_size: 0,
         access_type: u8::from(mode),
         write_access: 0,
         reserved1: 0,
         reserved2: 0,
     };
 
-    let _result = unsafe { unsafe_read_write_pyhs(fd, &mut data_transfer) }?;
-
-    match mode {
-        AccessMode::Buffer => {
-            // Vector does not know that kernel gave it some data. Still
-            // thinks it is empty. Luckily the kernel told us how much it
-            // wrote.
-            unsafe {
-                // cannot panic on 64 bit
-                mem.set_len(data_transfer.readbuffer_size.try_into().unwrap())
-            };
-            Ok(mem)
-        }
-        _ => Ok(
-            data_transfer.out_value.to_le_bytes()[..mode.size().unwrap()]
-                .into(),
-        ),
-    }
+    transport.read_write_phys(&mut data_transfer)?;
+
+    Ok(data_transfer.out_value)
+}
+
+/// Read into `buf`, returns the number of bytes that the driver read
+pub fn read_buffer(
+    transport: &dyn Transport,
+    address: u64,
+    buf: &mut [u8],
+) -> Result<usize, nix::errno::Errno> {
+    let mut data_transfer = bindings::LINPMEM_DATA_TRANSFER {
+        phys_address: address,
+        out_value: 0,
+        readbuffer: buf.as_mut_ptr() as *mut std::ffi::c_void,
+        readbuffer_size: buf.len() as u64,
+        access_type: u8::from(AccessMode::Buffer),
+        write_access: 0,
+        reserved1: 0,
+        reserved2: 0,
+    };
+
+    transport.read_write_phys(&mut data_transfer)?;
+
+    Ok((data_transfer.readbuffer_size as usize).min(buf.len()))
 }
 
+/// Translate `virt_address` with the page tables at `associated_cr3`, zero
+/// selects the page tables of the current process
+///
+/// Returns the physical address and the kernel virtual address of the entry
+/// that maps the page.
 pub fn v_to_p(
-    fd: fd::RawFd,
+    transport: &dyn Transport,
     virt_address: u64,
-    pid: Option<u32>,
-) -> Result<u64, nix::errno::Errno> {
+    associated_cr3: u64,
+) -> Result<(u64, u64), nix::errno::Errno> {
     let mut data_transfer = bindings::LINPMEM_VTOP_INFO {
         virt_address,
-        associated_cr3: match pid {
-            Some(pid) => cr3(fd, Some(pid))?,
-            None => 0,
-        },
+        associated_cr3,
         phys_address: 0,
         ppte: ptr::null_mut(),
     };
 
-    let _result = unsafe { unsafe_v_to_p(fd, &mut data_transfer) }?;
+    transport.v_to_p(&mut data_transfer)?;
 
-    Ok(data_transfer.phys_address)
+    Ok((data_transfer.phys_address, data_transfer.ppte as u64))
 }
 
-pub fn cr3(fd: fd::RawFd, pid: Option<u32>) -> Result<u64, nix::errno::Errno> {
+pub fn cr3(
+    transport: &dyn Transport,
+    pid: Option<u32>,
+) -> Result<u64, nix::errno::Errno> {
     let mut data_transfer = bindings::LINPMEM_CR3_INFO {
         target_process: pid.unwrap_or(0) as u64,
         result_cr3: 0,
     };
 
-    let _result = unsafe { unsafe_cr3(fd, &mut data_transfer) }?;
+    transport.cr3(&mut data_transfer)?;
 
     Ok(data_transfer.result_cr3)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use nix::errno::Errno;
+
+    /// Driver that serves data transfers from memory that starts at
+    /// physical address zero
+    #[derive(Default)]
+    pub(crate) struct FakeDriver {
+        pub memory: Vec<u8>,
+        /// Size that buffer reads report instead of the size of the buffer
+        pub reported_size: Option<u64>,
+        /// Value of cr3 of every process
+        pub cr3: u64,
+        /// Physical address and address of the entry that every virtual
+        /// address translates to
+        pub translation: Option<(u64, u64)>,
+    }
+
+    impl Transport for FakeDriver {
+        fn read_write_phys(
+            &self,
+            data: &mut bindings::LINPMEM_DATA_TRANSFER,
+        ) -> nix::Result<()> {
+            let size = [
+                AccessMode::Byte,
+                AccessMode::Word,
+                AccessMode::Dword,
+                AccessMode::Qword,
+            ]
+            .into_iter()
+            .find(|mode| u8::from(*mode) == data.access_type)
+            .map(|mode| mode.size().unwrap())
+            .unwrap_or(data.readbuffer_size as usize);
+
+            let start = data.phys_address as usize;
+            let mem = start
+                .checked_add(size)
+                .and_then(|end| self.memory.get(start..end))
+                .ok_or(Errno::EINVAL)?;
+            if data.access_type == u8::from(AccessMode::Buffer) {
+                // SAFETY: the caller passes a buffer of `size` bytes
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        mem.as_ptr(),
+                        data.readbuffer as *mut u8,
+                        size,
+                    )
+                };
+                data.readbuffer_size =
+                    self.reported_size.unwrap_or(size as u64);
+            } else {
+                let mut value = [0u8; 8];
+                value[..size].copy_from_slice(mem);
+                data.out_value = u64::from_le_bytes(value);
+            }
+
+            Ok(())
+        }
+
+        fn v_to_p(
+            &self,
+            data: &mut bindings::LINPMEM_VTOP_INFO,
+        ) -> nix::Result<()> {
+            let (phys_address, ppte) = self.translation.ok_or(Errno::EINVAL)?;
+            data.phys_address = phys_address;
+            data.ppte = ppte as *mut _;
+
+            Ok(())
+        }
+
+        fn cr3(
+            &self,
+            data: &mut bindings::LINPMEM_CR3_INFO,
+        ) -> nix::Result<()> {
+            data.result_cr3 = self.cr3;
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_buffer_length() {
+        let driver = FakeDriver {
+            memory: (0..=255).collect(),
+            reported_size: Some(0x100),
+            ..Default::default()
+        };
+        let mut buf = [0u8; 0x10];
+
+        // The driver never reports more bytes than fit into the buffer
+        assert_eq!(read_buffer(&driver, 0x20, &mut buf), Ok(0x10));
+        assert_eq!(buf[0], 0x20);
+
+        let driver = FakeDriver {
+            reported_size: Some(4),
+            ..driver
+        };
+        assert_eq!(read_buffer(&driver, 0x20, &mut buf), Ok(4));
+        assert_eq!(read_buffer(&driver, 0xf8, &mut buf), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn read_values() {
+        let driver = FakeDriver {
+            memory: (0..=255).collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(read_value(&driver, 0x10, AccessMode::Byte), Ok(0x10));
+        assert_eq!(read_value(&driver, 0x10, AccessMode::Word), Ok(0x1110));
+        assert_eq!(
+            read_value(&driver, 0x10, AccessMode::Dword),
+            Ok(0x13121110)
+        );
+        assert_eq!(
+            read_value(&driver, 0x10, AccessMode::Qword),
+            Ok(0x1716151413121110)
+        );
+        assert_eq!(
+            read_value(&driver, 0x10, AccessMode::Buffer),
+            Err(Errno::EINVAL)
+        );
+    }
+}
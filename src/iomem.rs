@@ -0,0 +1,176 @@
+//! Parser for the physical address space layout exported in `/proc/iomem`
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+
+/// Kernel's view of the physical address space
+pub const IOMEM_PATH: &str = "/proc/iomem";
+/// Name of resources that are backed by usable RAM
+pub const SYSTEM_RAM: &str = "System RAM";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Inclusive range of physical addresses
+pub struct PhysRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl PhysRange {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self { start, end }
+    }
+
+    /// Number of bytes in the range
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+impl fmt::Display for PhysRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:016x}-0x{:016x}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A single line of the resource tree
+pub struct IomemEntry {
+    pub range: PhysRange,
+    pub name: String,
+    /// Nesting level in the resource tree, top-level entries have depth zero
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Iomem {
+    entries: Vec<IomemEntry>,
+}
+
+impl Iomem {
+    /// Parse the layout of the running system
+    pub fn load() -> anyhow::Result<Self> {
+        Self::from_path(IOMEM_PATH)
+    }
+
+    /// Parse a live or previously saved copy of `/proc/iomem`
+    pub fn from_path(path: &str) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .context(format!("Failed to read {}", path))?;
+
+        Self::parse(&content).context(format!("Failed to parse {}", path))
+    }
+
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+
+        for (lineno, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = Self::parse_line(line).context(format!(
+                "Malformed line {}: '{}'",
+                lineno + 1,
+                line
+            ))?;
+            entries.push(entry);
+        }
+
+        // Unprivileged readers see all addresses as zero
+        if !entries.is_empty()
+            && entries
+                .iter()
+                .all(|e| e.range.start == 0 && e.range.end == 0)
+        {
+            bail!("All addresses are zero. Are you running as root?");
+        }
+
+        Ok(Self { entries })
+    }
+
+    fn parse_line(line: &str) -> anyhow::Result<IomemEntry> {
+        let indent = line.len() - line.trim_start().len();
+        let (range, name) = line
+            .trim_start()
+            .split_once(" : ")
+            .context("Missing resource name")?;
+        let (start, end) =
+            range.split_once('-').context("Missing address range")?;
+        let start = u64::from_str_radix(start, 16)?;
+        let end = u64::from_str_radix(end, 16)?;
+
+        if end < start {
+            bail!("Range ends before it starts");
+        }
+
+        Ok(IomemEntry {
+            range: PhysRange::new(start, end),
+            name: name.trim().to_string(),
+            depth: indent / 2,
+        })
+    }
+
+    pub fn entries(&self) -> &[IomemEntry] {
+        &self.entries
+    }
+
+    /// Top-level ranges that are backed by RAM, sorted by address
+    pub fn system_ram(&self) -> Vec<PhysRange> {
+        let mut ranges: Vec<PhysRange> = self
+            .entries
+            .iter()
+            .filter(|e| e.depth == 0 && e.name == SYSTEM_RAM)
+            .map(|e| e.range)
+            .collect();
+
+        ranges.sort_by_key(|r| r.start);
+
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IOMEM: &str = "\
+00000000-00000fff : Reserved
+00001000-0009ffff : System RAM
+000a0000-000fffff : Reserved
+  000f0000-000fffff : System ROM
+00100000-3ffeffff : System RAM
+  01000000-01e00fff : Kernel code
+  01e00000-01e00fff : System RAM
+fed00000-fed003ff : HPET 0
+";
+
+    #[test]
+    fn system_ram() {
+        let iomem = Iomem::parse(IOMEM).unwrap();
+
+        assert_eq!(iomem.entries().len(), 8);
+        assert_eq!(iomem.entries()[3].depth, 1);
+        assert_eq!(
+            iomem.system_ram(),
+            vec![
+                PhysRange::new(0x1000, 0x9ffff),
+                PhysRange::new(0x100000, 0x3ffeffff)
+            ]
+        );
+    }
+
+    #[test]
+    fn unprivileged() {
+        let iomem = "00000000-00000000 : System RAM\n\
+                     00000000-00000000 : Reserved\n";
+
+        assert!(Iomem::parse(iomem).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Iomem::parse("00001000 : System RAM\n").is_err());
+        assert!(Iomem::parse("2000-1000 : System RAM\n").is_err());
+    }
+}
@@ -2,31 +2,136 @@
  * SPDX-License-Identifier: MIT
  */
 
+mod acquire;
+/// cbindgen:ignore
+pub mod backend;
 mod cli;
+pub mod error;
+/// cbindgen:ignore
+pub mod image;
 pub mod insmod;
-mod ioctl;
+/// cbindgen:ignore
+pub mod ioctl;
+/// cbindgen:ignore
+pub mod iomem;
+mod maps;
+/// cbindgen:ignore
+pub mod paging;
+mod progress;
+mod read;
+/// cbindgen:ignore
+pub mod reader;
 mod utils;
+mod verify;
 
-use crate::cli::Subcommands;
-pub use crate::cli::{Cli, LoaderCli};
-use crate::ioctl::{Driver, IOCtlCmd};
+use crate::backend::Backend;
+pub use crate::cli::{AccessMode, Cli, LoaderCli};
+use crate::cli::{IOCtlCmd, ProgressMode, Subcommands};
+use crate::paging::PteInfo;
+use crate::progress::{Progress, RangeOutcome};
+use anyhow::Context;
 use std::error::Error;
+use std::io::{self, Write};
+
+/// Maximum size of a single read when dumping a buffer
+const BUFFER_CHUNK_SIZE: u64 = 0x100000;
+
+/// Write `size` bytes starting at physical `address` to stdout
+///
+/// Large buffers are read in chunks to be able to report progress.
+fn read_buffer(
+    backend: &dyn Backend,
+    address: u64,
+    size: u64,
+    progress: Option<ProgressMode>,
+) -> Result<(), Box<dyn Error>> {
+    let mut progress = Progress::new(progress, size);
+    let mut stdout = io::stdout().lock();
+    let mut done = 0;
+
+    while done < size {
+        let chunk_address = address + done;
+        let mem = backend
+            .read_phys(chunk_address, BUFFER_CHUNK_SIZE.min(size - done))
+            .context(format!("Failed to read 0x{:016x}", chunk_address))?;
+        if mem.is_empty() {
+            progress.end_range(RangeOutcome::Failed);
+            progress.finish();
+            return Err(format!(
+                "No data returned for 0x{:016x}",
+                chunk_address
+            )
+            .into());
+        }
+
+        stdout.write_all(&mem)?;
+        progress.advance(chunk_address, mem.len() as u64);
+        done += mem.len() as u64;
+    }
+    progress.end_range(RangeOutcome::Read);
+    progress.finish();
+
+    Ok(())
+}
+
+/// Print a decoded page-table entry
+fn print_pte(info: &PteInfo) {
+    println!(
+        "PTE at 0x{:016x} (physical 0x{:016x}): 0x{:016x}",
+        info.address, info.phys_address, info.pte.0
+    );
+    println!(
+        "  page frame 0x{:x}, page size {}",
+        info.pte.pfn(info.page_size),
+        info.page_size
+    );
+    println!(
+        "  flags: {}",
+        info.pte.flag_names(info.page_size).join(", ")
+    );
+}
 
 pub fn run(cli: &Cli) -> Result<(), Box<dyn Error>> {
     if let Some(subcommand) = &cli.subcommand {
         return match subcommand {
             Subcommands::Insmod(insmod_cli) => insmod::run(insmod_cli),
+            Subcommands::Acquire(acquire_cli) => {
+                acquire::run(acquire_cli, cli.backend, cli.progress)
+            }
+            Subcommands::Verify(verify_cli) => verify::run(verify_cli),
+            Subcommands::Read(read_cli) => {
+                read::run(read_cli, cli.backend, cli.progress)
+            }
+            Subcommands::Maps(maps_cli) => maps::run(maps_cli, cli.backend),
+            Subcommands::Modinfo(modinfo_cli) => {
+                insmod::run_modinfo(modinfo_cli)
+            }
+            Subcommands::Status => insmod::run_status(),
         };
     }
 
     let cmd = IOCtlCmd::from_cli(cli)?;
-    let drv = Driver::build()?;
+    let backend = backend::open(cli.backend)?;
 
     match cmd {
-        IOCtlCmd::Cr3(pid) => Ok(drv.cr3(pid)?),
-        IOCtlCmd::VtoP(virt_address, pid) => Ok(drv.v_to_p(virt_address, pid)?),
-        IOCtlCmd::ReadPhys(address, mode, size) => {
-            Ok(drv.read_phys(address, mode, size)?)
+        IOCtlCmd::Cr3(pid) => {
+            println!("0x{:016x}", backend.cr3(pid)?);
+        }
+        IOCtlCmd::VtoP(virt_address, pid, false) => {
+            println!("0x{:016x}", backend.v_to_p(virt_address, pid)?);
+        }
+        IOCtlCmd::VtoP(virt_address, pid, true) => {
+            let info = backend.pte(virt_address, pid)?;
+            println!("0x{:016x}", info.translated);
+            print_pte(&info);
+        }
+        IOCtlCmd::ReadPhys(address, AccessMode::Buffer, Some(size)) => {
+            read_buffer(backend.as_ref(), address, size, cli.progress)?;
+        }
+        IOCtlCmd::ReadPhys(address, mode, _) => {
+            io::stdout().write_all(&backend.read_value(address, mode)?)?;
         }
     }
+
+    Ok(())
 }
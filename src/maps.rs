@@ -0,0 +1,162 @@
+//! Listing of the mappings of a process, as found in its page tables
+
+use crate::backend;
+use crate::cli::{BackendKind, MapsCli};
+use crate::paging::{Mapping, PageTableWalker, PagingMode};
+use anyhow::Context;
+use std::error::Error;
+use std::fs;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How much of a mapping is listed in /proc/PID/maps
+enum Coverage {
+    Full,
+    Partial,
+    None,
+}
+
+/// Parse the address ranges of /proc/PID/maps
+fn parse_proc_maps(maps: &str) -> anyhow::Result<Vec<Range<u64>>> {
+    maps.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let range = line.split_whitespace().next().unwrap_or_default();
+            let (start, end) = range
+                .split_once('-')
+                .context(format!("Malformed mapping: {}", line))?;
+
+            Ok(u64::from_str_radix(start, 16)
+                .context(format!("Malformed mapping: {}", line))?
+                ..u64::from_str_radix(end, 16)
+                    .context(format!("Malformed mapping: {}", line))?)
+        })
+        .collect()
+}
+
+fn coverage(mapping: &Mapping, reported: &[Range<u64>]) -> Coverage {
+    let covered: u64 = reported
+        .iter()
+        .map(|r| {
+            r.end
+                .min(mapping.end)
+                .saturating_sub(r.start.max(mapping.start))
+        })
+        .sum();
+
+    match covered {
+        0 => Coverage::None,
+        c if c >= mapping.end - mapping.start => Coverage::Full,
+        _ => Coverage::Partial,
+    }
+}
+
+/// Coverage of `mapping` by /proc/PID/maps, which only lists mappings of
+/// user space
+fn check(mapping: &Mapping, reported: &[Range<u64>]) -> Option<Coverage> {
+    mapping.user.then(|| coverage(mapping, reported))
+}
+
+pub fn run(cli: &MapsCli, backend: BackendKind) -> Result<(), Box<dyn Error>> {
+    let backend = backend::open(backend)?;
+    let cr3 = backend.cr3(cli.pid).context("Failed to query cr3")?;
+    let mode = PagingMode::detect().context("Failed to detect paging mode")?;
+    let walker = PageTableWalker::new(backend.as_ref(), cr3, mode);
+
+    let reported = if cli.check {
+        let path = match cli.pid {
+            Some(pid) => format!("/proc/{}/maps", pid),
+            None => String::from("/proc/self/maps"),
+        };
+        let maps = fs::read_to_string(&path)
+            .context(format!("Failed to read {}", path))?;
+        Some(parse_proc_maps(&maps)?)
+    } else {
+        None
+    };
+
+    let mappings = walker
+        .mappings(cli.kernel)
+        .context(format!("Failed to walk page tables at 0x{:016x}", cr3))?;
+    let mut unreported = 0;
+
+    for mapping in &mappings {
+        let note = match reported.as_ref().and_then(|r| check(mapping, r)) {
+            Some(Coverage::None) => "  not in maps",
+            Some(Coverage::Partial) => "  partially in maps",
+            _ => "",
+        };
+        if !note.is_empty() {
+            unreported += 1;
+        }
+
+        println!(
+            "0x{:016x}-0x{:016x} {} {} {:>8}{}",
+            mapping.start,
+            mapping.end,
+            mapping.permissions(),
+            mapping.page_size,
+            mapping.pages(),
+            note
+        );
+    }
+    if reported.is_some() {
+        eprintln!(
+            "{} user mappings, {} not or only partially reported by the \
+             kernel",
+            mappings.iter().filter(|m| m.user).count(),
+            unreported
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paging::PageSize;
+
+    #[test]
+    fn proc_maps() {
+        let reported = parse_proc_maps(
+            "555555554000-555555556000 r-xp 00000000 08:01 42 /usr/bin/cat\n\
+             7ffff7fc1000-7ffff7fc5000 r--p 00000000 00:00 0  [vvar]\n",
+        )
+        .unwrap();
+        assert_eq!(reported[0], 0x555555554000..0x555555556000);
+
+        let mapping = |start, end| Mapping {
+            start,
+            end,
+            page_size: PageSize::Size4K,
+            writable: false,
+            user: true,
+            no_execute: false,
+        };
+        assert_eq!(
+            coverage(&mapping(0x555555554000, 0x555555555000), &reported),
+            Coverage::Full
+        );
+        assert_eq!(
+            coverage(&mapping(0x555555555000, 0x555555557000), &reported),
+            Coverage::Partial
+        );
+        assert_eq!(
+            coverage(&mapping(0x10000, 0x11000), &reported),
+            Coverage::None
+        );
+
+        // Kernel mappings are never listed, thus, not checked
+        let kernel = Mapping {
+            user: false,
+            ..mapping(0xffff_8880_0000_0000, 0xffff_8880_0020_0000)
+        };
+        assert_eq!(check(&kernel, &reported), None);
+        assert_eq!(
+            check(&mapping(0x10000, 0x11000), &reported),
+            Some(Coverage::None)
+        );
+        assert!(parse_proc_maps("garbage").is_err());
+    }
+}
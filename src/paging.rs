@@ -0,0 +1,649 @@
+//! Translation of virtual addresses by walking x86-64 page tables
+//!
+//! Unlike the driver's translation ioctl, the walker returns the entry that
+//! maps the page, which tells whether the page is writable, executable,
+//! accessible from user mode, and so on. Page tables are read through a
+//! [`Backend`], so the walker also works on images.
+
+use crate::backend::Backend;
+use log::warn;
+use std::fmt;
+use std::fs;
+use std::io;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+/// Physical address bits of cr3 and of page-table entries
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const ENTRIES_PER_TABLE: u64 = 512;
+pub(crate) const CPUINFO_PATH: &str = "/proc/cpuinfo";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// PML4, PDPT, PD, PT with 48-bit virtual addresses
+    FourLevel,
+    /// PML5 on top of 4-level paging with 57-bit virtual addresses (LA57)
+    FiveLevel,
+}
+
+impl PagingMode {
+    /// Paging mode of the running kernel
+    ///
+    /// The kernel only reports the `la57` CPU flag if it enabled 5-level
+    /// paging.
+    pub fn detect() -> io::Result<Self> {
+        let cpuinfo = fs::read_to_string(CPUINFO_PATH)?;
+        let la57 = cpuinfo
+            .lines()
+            .filter(|line| line.starts_with("flags"))
+            .any(|line| line.split_whitespace().any(|flag| flag == "la57"));
+
+        Ok(if la57 {
+            Self::FiveLevel
+        } else {
+            Self::FourLevel
+        })
+    }
+
+    pub fn levels(&self) -> u32 {
+        match self {
+            Self::FourLevel => 4,
+            Self::FiveLevel => 5,
+        }
+    }
+
+    /// Number of implemented bits of virtual addresses
+    pub fn address_bits(&self) -> u32 {
+        12 + 9 * self.levels()
+    }
+
+    /// Whether bits above the implemented ones are copies of the highest one
+    pub fn is_canonical(&self, virt_address: u64) -> bool {
+        let shift = 64 - self.address_bits();
+
+        ((virt_address << shift) as i64 >> shift) as u64 == virt_address
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Self::Size4K => 0x1000,
+            Self::Size2M => 0x200000,
+            Self::Size1G => 0x40000000,
+        }
+    }
+}
+
+impl fmt::Display for PageSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Size4K => write!(f, "4K"),
+            Self::Size2M => write!(f, "2M"),
+            Self::Size1G => write!(f, "1G"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Page-table entry
+pub struct Pte(pub u64);
+
+impl Pte {
+    const PRESENT: u64 = 1 << 0;
+    const WRITABLE: u64 = 1 << 1;
+    const USER: u64 = 1 << 2;
+    const WRITE_THROUGH: u64 = 1 << 3;
+    const CACHE_DISABLE: u64 = 1 << 4;
+    const ACCESSED: u64 = 1 << 5;
+    const DIRTY: u64 = 1 << 6;
+    /// Page size in PDPT and PD entries, PAT in PT entries
+    const LARGE: u64 = 1 << 7;
+    const GLOBAL: u64 = 1 << 8;
+    const LARGE_PAT: u64 = 1 << 12;
+    const NO_EXECUTE: u64 = 1 << 63;
+
+    pub fn present(&self) -> bool {
+        self.0 & Self::PRESENT != 0
+    }
+
+    pub fn writable(&self) -> bool {
+        self.0 & Self::WRITABLE != 0
+    }
+
+    pub fn user(&self) -> bool {
+        self.0 & Self::USER != 0
+    }
+
+    pub fn write_through(&self) -> bool {
+        self.0 & Self::WRITE_THROUGH != 0
+    }
+
+    pub fn cache_disabled(&self) -> bool {
+        self.0 & Self::CACHE_DISABLE != 0
+    }
+
+    pub fn accessed(&self) -> bool {
+        self.0 & Self::ACCESSED != 0
+    }
+
+    /// Only meaningful for entries that map a page
+    pub fn dirty(&self) -> bool {
+        self.0 & Self::DIRTY != 0
+    }
+
+    pub fn global(&self) -> bool {
+        self.0 & Self::GLOBAL != 0
+    }
+
+    pub fn no_execute(&self) -> bool {
+        self.0 & Self::NO_EXECUTE != 0
+    }
+
+    /// Whether a PDPT or PD entry maps a 1G or 2M page
+    pub fn large(&self) -> bool {
+        self.0 & Self::LARGE != 0
+    }
+
+    /// PAT bit of an entry that maps a page of the given size
+    pub fn pat(&self, size: PageSize) -> bool {
+        match size {
+            PageSize::Size4K => self.0 & Self::LARGE != 0,
+            _ => self.0 & Self::LARGE_PAT != 0,
+        }
+    }
+
+    /// Physical address of the next table or of a 4K page
+    pub fn address(&self) -> u64 {
+        self.0 & ADDRESS_MASK
+    }
+
+    /// Physical address of a page of the given size
+    pub fn page_address(&self, size: PageSize) -> u64 {
+        self.0 & ADDRESS_MASK & !(size.bytes() - 1)
+    }
+
+    /// Page frame number of a page of the given size, in units of 4K
+    pub fn pfn(&self, size: PageSize) -> u64 {
+        self.page_address(size) / PAGE_SIZE
+    }
+
+    /// Names of the flags that are set in an entry that maps a page of the
+    /// given size
+    pub fn flag_names(&self, size: PageSize) -> Vec<&'static str> {
+        [
+            (self.present(), "present"),
+            (self.writable(), "writable"),
+            (self.user(), "user"),
+            (self.write_through(), "write-through"),
+            (self.cache_disabled(), "cache-disabled"),
+            (self.accessed(), "accessed"),
+            (self.dirty(), "dirty"),
+            (self.pat(size), "pat"),
+            (self.global(), "global"),
+            (self.no_execute(), "no-execute"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect()
+    }
+}
+
+/// Permissions in the style of /proc/PID/maps, e.g., `rwxu`
+fn permissions(writable: bool, no_execute: bool, user: bool) -> String {
+    format!(
+        "r{}{}{}",
+        if writable { 'w' } else { '-' },
+        if no_execute { '-' } else { 'x' },
+        if user { 'u' } else { 'k' },
+    )
+}
+
+impl fmt::Display for Pte {
+    /// Flags in the style of /proc/PID/maps, e.g., `rwxu`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&permissions(
+            self.writable(),
+            self.no_execute(),
+            self.user(),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Entry that maps a page, as reported by the driver
+pub struct PteInfo {
+    /// Kernel virtual address of the entry
+    pub address: u64,
+    /// Physical address of the entry
+    pub phys_address: u64,
+    pub pte: Pte,
+    pub page_size: PageSize,
+    /// Physical address that the virtual address translates to
+    pub translated: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Result of a successful translation
+pub struct Translation {
+    pub virt_address: u64,
+    pub phys_address: u64,
+    pub page_size: PageSize,
+    /// Entry that maps the page
+    pub pte: Pte,
+    /// Physical address of that entry
+    pub pte_address: u64,
+    /// Effective permissions, i.e., combined over all levels
+    pub writable: bool,
+    pub user: bool,
+    pub no_execute: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Virtually contiguous pages of the same size and permissions
+pub struct Mapping {
+    pub start: u64,
+    /// First address after the mapping
+    pub end: u64,
+    pub page_size: PageSize,
+    pub writable: bool,
+    pub user: bool,
+    pub no_execute: bool,
+}
+
+impl Mapping {
+    pub fn pages(&self) -> u64 {
+        (self.end - self.start) / self.page_size.bytes()
+    }
+
+    /// Whether `next` directly follows with the same page size and
+    /// permissions
+    fn continued_by(&self, next: &Mapping) -> bool {
+        self.end == next.start
+            && self.page_size == next.page_size
+            && self.writable == next.writable
+            && self.user == next.user
+            && self.no_execute == next.no_execute
+    }
+
+    /// Permissions in the style of /proc/PID/maps, e.g., `rwxu`
+    pub fn permissions(&self) -> String {
+        permissions(self.writable, self.no_execute, self.user)
+    }
+}
+
+/// Page-table walker for the address space whose root is given by cr3
+pub struct PageTableWalker<'a> {
+    backend: &'a dyn Backend,
+    root: u64,
+    mode: PagingMode,
+}
+
+impl<'a> PageTableWalker<'a> {
+    /// Create a walker for the page tables at `cr3`
+    ///
+    /// The PCID and the other low bits of cr3, as well as the no-flush bit,
+    /// are masked.
+    pub fn new(backend: &'a dyn Backend, cr3: u64, mode: PagingMode) -> Self {
+        Self {
+            backend,
+            root: cr3 & ADDRESS_MASK,
+            mode,
+        }
+    }
+
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
+    /// Physical address of the top-level table
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Read the entry at physical `address`
+    pub(crate) fn read_entry(&self, address: u64) -> io::Result<Pte> {
+        let data = self.backend.read_phys(address, 8)?;
+        let data: [u8; 8] = data.as_slice().try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Short read of entry at 0x{:016x}", address),
+            )
+        })?;
+
+        Ok(Pte(u64::from_le_bytes(data)))
+    }
+
+    /// Read all entries of the table at physical `address`
+    fn read_table(&self, address: u64) -> io::Result<Vec<Pte>> {
+        let mut data = Vec::with_capacity(PAGE_SIZE as usize);
+
+        while (data.len() as u64) < PAGE_SIZE {
+            let offset = data.len() as u64;
+            let chunk = self
+                .backend
+                .read_phys(address + offset, PAGE_SIZE - offset)?;
+            if chunk.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Short read of table at 0x{:016x}", address),
+                ));
+            }
+            data.extend(chunk);
+        }
+
+        Ok(data
+            .chunks_exact(8)
+            .map(|entry| {
+                Pte(u64::from_le_bytes(
+                    entry.try_into().expect("BUG: entry is not 8 bytes"),
+                ))
+            })
+            .collect())
+    }
+
+    /// Enumerate all mapped pages, coalesced into mappings
+    ///
+    /// Unless `kernel` is set, only pages that are accessible from user mode
+    /// are included. Tables that cannot be read are skipped with a warning.
+    pub fn mappings(&self, kernel: bool) -> io::Result<Vec<Mapping>> {
+        let mut mappings = Vec::new();
+        let permissions = Mapping {
+            start: 0,
+            end: 0,
+            page_size: PageSize::Size4K,
+            writable: true,
+            user: true,
+            no_execute: false,
+        };
+
+        self.walk_table(
+            self.root,
+            self.mode.levels(),
+            0,
+            permissions,
+            kernel,
+            &mut mappings,
+        )?;
+
+        Ok(mappings)
+    }
+
+    /// Collect the mappings of the table at `table` on `level`, which maps
+    /// addresses starting at `base`
+    ///
+    /// `permissions` are the permissions that are effective for the table.
+    fn walk_table(
+        &self,
+        table: u64,
+        level: u32,
+        base: u64,
+        permissions: Mapping,
+        kernel: bool,
+        mappings: &mut Vec<Mapping>,
+    ) -> io::Result<()> {
+        let top = level == self.mode.levels();
+        let entries = match self.read_table(table) {
+            Ok(entries) => entries,
+            // The root table must be readable
+            Err(err) if top => return Err(err),
+            Err(err) => {
+                warn!("Skipping table at 0x{:016x}: {}", table, err);
+                return Ok(());
+            }
+        };
+        let shift = 12 + 9 * (level - 1);
+
+        for (index, pte) in entries.into_iter().enumerate() {
+            // The upper half of the address space belongs to the kernel
+            if top && !kernel && index as u64 >= ENTRIES_PER_TABLE / 2 {
+                break;
+            }
+            if !pte.present() {
+                continue;
+            }
+
+            let mut start = base | ((index as u64) << shift);
+            if top {
+                // Sign-extend to a canonical address
+                let unused = 64 - self.mode.address_bits();
+                start = ((start << unused) as i64 >> unused) as u64;
+            }
+            let permissions = Mapping {
+                writable: permissions.writable && pte.writable(),
+                user: permissions.user && pte.user(),
+                no_execute: permissions.no_execute || pte.no_execute(),
+                ..permissions
+            };
+            if !kernel && !permissions.user {
+                continue;
+            }
+
+            let page_size = match level {
+                1 => PageSize::Size4K,
+                2 if pte.large() => PageSize::Size2M,
+                3 if pte.large() => PageSize::Size1G,
+                _ => {
+                    self.walk_table(
+                        pte.address(),
+                        level - 1,
+                        start,
+                        permissions,
+                        kernel,
+                        mappings,
+                    )?;
+                    continue;
+                }
+            };
+            let mapping = Mapping {
+                start,
+                end: start + page_size.bytes(),
+                page_size,
+                ..permissions
+            };
+
+            match mappings.last_mut() {
+                Some(last) if last.continued_by(&mapping) => {
+                    last.end = mapping.end
+                }
+                _ => mappings.push(mapping),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Translate `virt_address`
+    ///
+    /// Returns `None` if the address is not mapped.
+    pub fn translate(
+        &self,
+        virt_address: u64,
+    ) -> io::Result<Option<Translation>> {
+        if !self.mode.is_canonical(virt_address) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Address 0x{:016x} is not canonical", virt_address),
+            ));
+        }
+
+        let mut table = self.root;
+        let mut writable = true;
+        let mut user = true;
+        let mut no_execute = false;
+
+        for level in (1..=self.mode.levels()).rev() {
+            let shift = 12 + 9 * (level - 1);
+            let index = (virt_address >> shift) % ENTRIES_PER_TABLE;
+            let pte_address = table + index * 8;
+            let pte = self.read_entry(pte_address)?;
+
+            if !pte.present() {
+                return Ok(None);
+            }
+            writable &= pte.writable();
+            user &= pte.user();
+            no_execute |= pte.no_execute();
+
+            let page_size = match level {
+                1 => Some(PageSize::Size4K),
+                2 if pte.large() => Some(PageSize::Size2M),
+                3 if pte.large() => Some(PageSize::Size1G),
+                _ => None,
+            };
+            if let Some(page_size) = page_size {
+                return Ok(Some(Translation {
+                    virt_address,
+                    phys_address: pte.page_address(page_size)
+                        | (virt_address & (page_size.bytes() - 1)),
+                    page_size,
+                    pte,
+                    pte_address,
+                    writable,
+                    user,
+                    no_execute,
+                }));
+            }
+            table = pte.address();
+        }
+
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::image::ImageBackend;
+    use std::os::unix::fs::FileExt;
+
+    const P: u64 = Pte::PRESENT;
+    const W: u64 = Pte::WRITABLE;
+    const U: u64 = Pte::USER;
+    const L: u64 = Pte::LARGE;
+
+    /// Padded image with the given 64-bit values at physical addresses
+    fn image(entries: &[(u64, u64)]) -> ImageBackend {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(0x10000).unwrap();
+        for (address, value) in entries {
+            file.write_all_at(&value.to_le_bytes(), *address).unwrap();
+        }
+
+        ImageBackend::detect(file).unwrap()
+    }
+
+    #[test]
+    fn four_level() {
+        // 4K page at 0x4000_1000, 2M page at 0x4020_0000, 1G page at
+        // 0x8000_0000 with the tables at 0x1000 (PML4), 0x2000 (PDPT),
+        // 0x3000 (PD), and 0x4000 (PT)
+        let backend = image(&[
+            (0x1000, 0x2000 | P | W | U),
+            (0x2000 + 8, 0x3000 | P | W | U),
+            (0x2000 + 2 * 8, 0x1_c000_0000 | P | U | L),
+            (0x3000, 0x4000 | P | W | U),
+            (0x3000 + 8, 0x60_0000 | P | U | L | Pte::NO_EXECUTE),
+            // Bit 7 is the PAT bit in entries that map 4K pages
+            (0x4000 + 8, 0x9000 | P | W | U | L | Pte::DIRTY),
+        ]);
+        // PCID and no-flush bits are ignored
+        let walker = PageTableWalker::new(
+            &backend,
+            (1 << 63) | 0x1000 | 0x123,
+            PagingMode::FourLevel,
+        );
+
+        let t = walker.translate(0x4000_1234).unwrap().unwrap();
+        assert_eq!(t.phys_address, 0x9234);
+        assert_eq!(t.page_size, PageSize::Size4K);
+        assert_eq!(t.pte_address, 0x4008);
+        assert!(t.writable && t.user && !t.no_execute && t.pte.dirty());
+        assert!(t.pte.pat(PageSize::Size4K));
+
+        let t = walker.translate(0x4021_2345).unwrap().unwrap();
+        assert_eq!(t.phys_address, 0x61_2345);
+        assert_eq!(t.page_size, PageSize::Size2M);
+        assert!(!t.writable && t.no_execute);
+        assert_eq!(t.pte.to_string(), "r--u");
+        assert!(t.pte.pat(PageSize::Size4K));
+        assert!(!t.pte.pat(PageSize::Size2M));
+        assert_eq!(t.pte.pfn(t.page_size), 0x600);
+        assert_eq!(
+            t.pte.flag_names(t.page_size),
+            ["present", "user", "no-execute"]
+        );
+
+        let t = walker.translate(0x8123_4567).unwrap().unwrap();
+        assert_eq!(t.phys_address, 0x1_c123_4567);
+        assert_eq!(t.page_size, PageSize::Size1G);
+
+        assert_eq!(walker.translate(0x4000_2000).unwrap(), None);
+        assert!(walker.translate(0x0000_8000_0000_0000).is_err());
+        assert!(walker.translate(0xffff_8000_0000_0000).is_ok());
+    }
+
+    #[test]
+    fn five_level() {
+        let virt = 0x0002_0000_0000_1000;
+        let backend = image(&[
+            (0x1000 + 2 * 8, 0x2000 | P | W),
+            (0x2000, 0x3000 | P | W),
+            (0x3000, 0x4000 | P | W),
+            (0x4000, 0x5000 | P | W),
+            (0x5000 + 8, 0xa000 | P | W),
+        ]);
+        let walker =
+            PageTableWalker::new(&backend, 0x1000, PagingMode::FiveLevel);
+
+        let t = walker.translate(virt + 0x10).unwrap().unwrap();
+        assert_eq!(t.phys_address, 0xa010);
+        assert!(!t.user);
+        assert!(!PagingMode::FourLevel.is_canonical(virt));
+        assert!(PagingMode::FiveLevel.is_canonical(0xff00_0000_0000_0000));
+    }
+
+    #[test]
+    fn mappings() {
+        // Two adjacent 4K user pages, a kernel page, and a 2M user page
+        let backend = image(&[
+            (0x1000, 0x2000 | P | W | U),
+            (0x1000 + 256 * 8, 0x5000 | P | W),
+            (0x2000, 0x3000 | P | W | U),
+            (0x3000, 0x4000 | P | W | U),
+            (0x3000 + 8, 0x20_0000 | P | U | L),
+            (0x4000 + 8, 0x9000 | P | W | U),
+            (0x4000 + 2 * 8, 0xa000 | P | W | U),
+            (0x4000 + 3 * 8, 0xb000 | P | W),
+            (0x5000, 0x40_0000 | P | W | L),
+        ]);
+        let walker =
+            PageTableWalker::new(&backend, 0x1000, PagingMode::FourLevel);
+
+        let user = walker.mappings(false).unwrap();
+        assert_eq!(user.len(), 2);
+        assert_eq!((user[0].start, user[0].end), (0x1000, 0x3000));
+        assert_eq!(user[0].pages(), 2);
+        assert_eq!(user[0].permissions(), "rwxu");
+        assert_eq!(
+            (user[1].start, user[1].page_size),
+            (0x20_0000, PageSize::Size2M)
+        );
+        assert_eq!(user[1].permissions(), "r-xu");
+
+        let all = walker.mappings(true).unwrap();
+        assert_eq!(all.len(), 4);
+        assert_eq!(
+            (all[1].start, all[1].permissions()),
+            (0x3000, "rwxk".to_string())
+        );
+        assert_eq!(all[3].start, 0xffff_8000_0000_0000);
+        assert_eq!(all[3].page_size, PageSize::Size1G);
+    }
+}
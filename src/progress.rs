@@ -0,0 +1,179 @@
+//! Progress reports for long-running reads
+//!
+//! Reports are written to stderr, either as a single line of text that is
+//! updated in place, or as JSON lines that can be consumed by other programs.
+
+use crate::cli::ProgressMode;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two progress reports
+const INTERVAL: Duration = Duration::from_secs(1);
+const MIB: f64 = (1 << 20) as f64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeOutcome {
+    /// All data was read from memory
+    Read,
+    /// All data was already present in the output
+    Skipped,
+    /// Parts of the range could not be read
+    Failed,
+}
+
+pub struct Progress {
+    mode: Option<ProgressMode>,
+    /// Number of bytes that will be processed
+    total: u64,
+    /// Number of bytes processed so far
+    done: u64,
+    /// Number of bytes that were processed without reading them
+    skipped: u64,
+    /// Physical address of the last processed byte
+    address: u64,
+    start: Instant,
+    last_report: Option<Instant>,
+    ranges_read: usize,
+    ranges_skipped: usize,
+    ranges_failed: usize,
+}
+
+impl Progress {
+    pub fn new(mode: Option<ProgressMode>, total: u64) -> Self {
+        Self {
+            mode,
+            total,
+            done: 0,
+            skipped: 0,
+            address: 0,
+            start: Instant::now(),
+            last_report: None,
+            ranges_read: 0,
+            ranges_skipped: 0,
+            ranges_failed: 0,
+        }
+    }
+
+    /// Account for `size` bytes that were read starting at `address`
+    pub fn advance(&mut self, address: u64, size: u64) {
+        self.done += size;
+        self.address = address + size.saturating_sub(1);
+
+        let due = self
+            .last_report
+            .map(|last| last.elapsed() >= INTERVAL)
+            .unwrap_or(true);
+        if due {
+            self.report();
+        }
+    }
+
+    /// Account for `size` bytes that did not have to be read
+    pub fn skip(&mut self, address: u64, size: u64) {
+        self.skipped += size;
+        self.advance(address, size);
+    }
+
+    pub fn end_range(&mut self, outcome: RangeOutcome) {
+        match outcome {
+            RangeOutcome::Read => self.ranges_read += 1,
+            RangeOutcome::Skipped => self.ranges_skipped += 1,
+            RangeOutcome::Failed => self.ranges_failed += 1,
+        }
+    }
+
+    /// Throughput in bytes per second
+    fn rate(&self) -> f64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+
+        if elapsed > 0.0 {
+            (self.done - self.skipped) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimated time until completion in seconds
+    fn eta(&self) -> Option<u64> {
+        let rate = self.rate();
+
+        (rate > 0.0).then(|| {
+            (self.total.saturating_sub(self.done) as f64 / rate) as u64
+        })
+    }
+
+    fn report(&mut self) {
+        self.last_report = Some(Instant::now());
+
+        match self.mode {
+            None => {}
+            Some(ProgressMode::Text) => {
+                let percent = match self.total {
+                    0 => 100.0,
+                    total => self.done as f64 * 100.0 / total as f64,
+                };
+                let eta = match self.eta() {
+                    Some(eta) => format!("{:02}:{:02}", eta / 60, eta % 60),
+                    None => String::from("--:--"),
+                };
+                eprint!(
+                    "\r{:.1}/{:.1} MiB ({:.1}%) at 0x{:016x}, {:.1} MB/s, ETA {}  ",
+                    self.done as f64 / MIB,
+                    self.total as f64 / MIB,
+                    percent,
+                    self.address,
+                    self.rate() / 1e6,
+                    eta
+                );
+            }
+            Some(ProgressMode::Json) => {
+                eprintln!(
+                    "{}",
+                    json!({
+                        "type": "progress",
+                        "done": self.done,
+                        "total": self.total,
+                        "address": self.address,
+                        "rate": self.rate() as u64,
+                        "eta": self.eta(),
+                    })
+                );
+            }
+        }
+    }
+
+    /// Report the final state and a summary of all ranges
+    pub fn finish(&mut self) {
+        self.report();
+
+        let elapsed = self.start.elapsed().as_secs();
+        match self.mode {
+            None => {}
+            Some(ProgressMode::Text) => {
+                eprintln!();
+                eprintln!(
+                    "Ranges: {} read, {} skipped, {} failed; {:.1} MiB in {:02}:{:02}",
+                    self.ranges_read,
+                    self.ranges_skipped,
+                    self.ranges_failed,
+                    self.done as f64 / MIB,
+                    elapsed / 60,
+                    elapsed % 60
+                );
+            }
+            Some(ProgressMode::Json) => {
+                eprintln!(
+                    "{}",
+                    json!({
+                        "type": "summary",
+                        "ranges_read": self.ranges_read,
+                        "ranges_skipped": self.ranges_skipped,
+                        "ranges_failed": self.ranges_failed,
+                        "bytes": self.done,
+                        "elapsed": elapsed,
+                    })
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Reads from the virtual address space of a process
+
+use crate::backend;
+use crate::cli::{BackendKind, ProgressMode, ReadCli};
+use crate::progress::{Progress, RangeOutcome};
+use crate::reader::{UnreadablePolicy, VirtReader};
+use std::error::Error;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const BUFFER_SIZE: usize = 0x10000;
+
+/// Write `cli.size` bytes starting at virtual address `cli.virt` to stdout
+pub fn run(
+    cli: &ReadCli,
+    backend: BackendKind,
+    progress: Option<ProgressMode>,
+) -> Result<(), Box<dyn Error>> {
+    let backend = backend::open(backend)?;
+    let mut reader = VirtReader::new(backend.as_ref(), cli.pid)?.with_policy(
+        if cli.zero_fill {
+            UnreadablePolicy::ZeroFill
+        } else {
+            UnreadablePolicy::Error
+        },
+    );
+    reader.seek(SeekFrom::Start(cli.virt))?;
+
+    let mut progress = Progress::new(progress, cli.size);
+    let mut stdout = io::stdout().lock();
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut done = 0;
+
+    while done < cli.size {
+        let address = reader.position();
+        let size = (BUFFER_SIZE as u64).min(cli.size - done) as usize;
+        let read = match reader.read(&mut buf[..size]) {
+            Ok(read) => read,
+            Err(err) => {
+                progress.end_range(RangeOutcome::Failed);
+                progress.finish();
+                return Err(anyhow::Error::from(err)
+                    .context(format!(
+                        "Failed to read virtual 0x{:016x}",
+                        address
+                    ))
+                    .into());
+            }
+        };
+
+        stdout.write_all(&buf[..read])?;
+        progress.advance(address, read as u64);
+        done += read as u64;
+    }
+    progress.end_range(if reader.unreadable() > 0 {
+        RangeOutcome::Failed
+    } else {
+        RangeOutcome::Read
+    });
+    progress.finish();
+
+    Ok(())
+}
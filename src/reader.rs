@@ -0,0 +1,442 @@
+//! Adapters that expose memory through `std::io::Read` and `std::io::Seek`
+//!
+//! This lets parsers that work on files, as well as `io::copy`, operate on
+//! memory directly.
+
+use crate::backend::Backend;
+use crate::iomem::PhysRange;
+use crate::paging::PAGE_SIZE;
+use log::warn;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Maximum size of a single request to the backend
+const DEFAULT_CHUNK_SIZE: u64 = 0x100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What to do when a page cannot be read
+pub enum UnreadablePolicy {
+    /// Return zeros for the page, a `PhysReader` additionally needs an end,
+    /// otherwise it would never reach end-of-file
+    ZeroFill,
+    /// Fail the read
+    Error,
+}
+
+/// Reader whose position is a physical address
+pub struct PhysReader<'a> {
+    backend: &'a dyn Backend,
+    position: u64,
+    /// Reads at or beyond this address return end-of-file
+    end: Option<u64>,
+    policy: UnreadablePolicy,
+    chunk_size: u64,
+    /// Spans that were zero-filled so far, adjacent ones are merged
+    zero_filled: Vec<PhysRange>,
+}
+
+impl<'a> PhysReader<'a> {
+    /// Create a reader that starts at physical address zero and fails on
+    /// unreadable pages
+    pub fn new(backend: &'a dyn Backend) -> Self {
+        Self {
+            backend,
+            position: 0,
+            end: None,
+            policy: UnreadablePolicy::Error,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            zero_filled: Vec::new(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: UnreadablePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Treat `end` as the end of physical memory, which makes
+    /// `SeekFrom::End` available
+    pub fn with_end(mut self, end: u64) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    /// Limit the size of the requests to the backend
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Current physical address
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Number of bytes that were zero-filled so far
+    pub fn unreadable(&self) -> u64 {
+        self.zero_filled.iter().map(PhysRange::size).sum()
+    }
+
+    /// Spans of physical memory that were zero-filled so far
+    pub fn zero_filled(&self) -> &[PhysRange] {
+        &self.zero_filled
+    }
+
+    fn zero_fill(&mut self, address: u64, size: u64) {
+        match self.zero_filled.last_mut() {
+            Some(span) if span.end + 1 == address => span.end += size,
+            _ => self
+                .zero_filled
+                .push(PhysRange::new(address, address + size - 1)),
+        }
+    }
+}
+
+impl Read for PhysReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut size = (buf.len() as u64).min(self.chunk_size);
+        if let Some(end) = self.end {
+            size = size.min(end.saturating_sub(self.position));
+        }
+        if size == 0 {
+            return Ok(0);
+        }
+        let address = self.position;
+
+        let page_rest = (PAGE_SIZE - address % PAGE_SIZE).min(size);
+        let mut result = self.backend.read_phys(address, size);
+        let failed = !matches!(&result, Ok(mem) if !mem.is_empty());
+        if failed && size > page_rest {
+            // Only some of the pages might be unreadable
+            result = self.backend.read_phys(address, page_rest);
+        }
+
+        let read = match result {
+            Ok(mem) if !mem.is_empty() => {
+                let read = mem.len().min(size as usize);
+                buf[..read].copy_from_slice(&mem[..read]);
+                read
+            }
+            result => {
+                let err = result.err().unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("No data returned for 0x{:016x}", address),
+                    )
+                });
+                if self.policy == UnreadablePolicy::Error {
+                    return Err(err);
+                }
+                if self.end.is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Cannot zero-fill 0x{:016x} without an end of memory: {}",
+                            address, err
+                        ),
+                    ));
+                }
+
+                let read = page_rest as usize;
+                warn!(
+                    "Zero-filling 0x{:x} bytes at 0x{:016x}: {}",
+                    read, address, err
+                );
+                buf[..read].fill(0);
+                self.zero_fill(address, read as u64);
+                read
+            }
+        };
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for PhysReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.end, pos)?;
+
+        Ok(self.position)
+    }
+}
+
+/// Reader whose position is a virtual address in a process' address space
+///
+/// Each page is translated with the backend's `v_to_p_cr3` before it is read
+/// from physical memory. Reads do not cross page boundaries.
+pub struct VirtReader<'a> {
+    backend: &'a dyn Backend,
+    /// Page tables of the process, which are looked up once
+    cr3: u64,
+    position: u64,
+    policy: UnreadablePolicy,
+    /// Last translation, i.e., virtual and physical address of a page
+    translation: Option<(u64, u64)>,
+    unreadable: u64,
+}
+
+impl<'a> VirtReader<'a> {
+    /// Create a reader over the address space of process `pid` (default:
+    /// current process) that fails on unmapped or unreadable pages
+    pub fn new(backend: &'a dyn Backend, pid: Option<u32>) -> io::Result<Self> {
+        Ok(Self {
+            backend,
+            cr3: backend.cr3(pid)?,
+            position: 0,
+            policy: UnreadablePolicy::Error,
+            translation: None,
+            unreadable: 0,
+        })
+    }
+
+    pub fn with_policy(mut self, policy: UnreadablePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Current virtual address
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Number of bytes that were zero-filled so far
+    pub fn unreadable(&self) -> u64 {
+        self.unreadable
+    }
+
+    /// Physical address of the page that contains `page`
+    fn translate(&mut self, page: u64) -> io::Result<u64> {
+        match self.translation {
+            Some((virt, phys)) if virt == page => Ok(phys),
+            _ => {
+                let phys = self.backend.v_to_p_cr3(page, self.cr3)?;
+                self.translation = Some((page, phys));
+                Ok(phys)
+            }
+        }
+    }
+}
+
+impl Read for VirtReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let address = self.position;
+        let offset = address % PAGE_SIZE;
+        let size = (PAGE_SIZE - offset).min(buf.len() as u64);
+        if size == 0 {
+            return Ok(0);
+        }
+
+        let result = self
+            .translate(address - offset)
+            .and_then(|phys| self.backend.read_phys(phys + offset, size));
+        let read = match result {
+            Ok(mem) if !mem.is_empty() => {
+                let read = mem.len().min(size as usize);
+                buf[..read].copy_from_slice(&mem[..read]);
+                read
+            }
+            result => {
+                let err = result.err().unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("No data returned for 0x{:016x}", address),
+                    )
+                });
+                if self.policy == UnreadablePolicy::Error {
+                    return Err(err);
+                }
+
+                let read = size as usize;
+                warn!(
+                    "Zero-filling 0x{:x} bytes at virtual 0x{:016x}: {}",
+                    read, address, err
+                );
+                buf[..read].fill(0);
+                self.unreadable += read as u64;
+                read
+            }
+        };
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for VirtReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, Some(u64::MAX), pos)?;
+
+        Ok(self.position)
+    }
+}
+
+/// New position after seeking to `pos`
+fn seek_position(
+    position: u64,
+    end: Option<u64>,
+    pos: SeekFrom,
+) -> io::Result<u64> {
+    let (base, offset) = match pos {
+        SeekFrom::Start(offset) => return Ok(offset),
+        SeekFrom::Current(offset) => (position, offset),
+        SeekFrom::End(offset) => (
+            end.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "End of memory is unknown",
+                )
+            })?,
+            offset,
+        ),
+    };
+
+    base.checked_add_signed(offset).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::ops::Range;
+
+    /// Memory with a pattern and a range that cannot be read
+    struct TestMemory {
+        memory: Vec<u8>,
+        unreadable: Range<u64>,
+        /// Virtual and physical addresses of mapped pages
+        pages: Vec<(u64, u64)>,
+        cr3_lookups: Cell<usize>,
+    }
+
+    impl TestMemory {
+        fn new(size: u64, unreadable: Range<u64>) -> Self {
+            Self {
+                memory: (0..size).map(|a| (a ^ (a >> 8)) as u8).collect(),
+                unreadable,
+                pages: Vec::new(),
+                cr3_lookups: Cell::new(0),
+            }
+        }
+    }
+
+    impl Backend for TestMemory {
+        fn read_phys(&self, address: u64, size: u64) -> io::Result<Vec<u8>> {
+            let end = (address + size).min(self.memory.len() as u64);
+            if address < self.unreadable.end && self.unreadable.start < end {
+                return Err(io::Error::from_raw_os_error(14));
+            }
+
+            // Short reads at the end of each page
+            let end = end.min((address / PAGE_SIZE + 1) * PAGE_SIZE);
+            Ok(self.memory[address as usize..end as usize].to_vec())
+        }
+
+        fn v_to_p(&self, _: u64, _: Option<u32>) -> io::Result<u64> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn v_to_p_cr3(&self, virt: u64, cr3: u64) -> io::Result<u64> {
+            assert_eq!(cr3, 0x1000);
+            let offset = virt % PAGE_SIZE;
+            self.pages
+                .iter()
+                .find(|(page, _)| *page == virt - offset)
+                .map(|(_, phys)| phys + offset)
+                .ok_or_else(|| io::Error::from_raw_os_error(22))
+        }
+
+        fn cr3(&self, pid: Option<u32>) -> io::Result<u64> {
+            self.cr3_lookups.set(self.cr3_lookups.get() + 1);
+            match pid {
+                Some(1) => Ok(0x1000),
+                _ => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn read_and_seek() {
+        let memory = TestMemory::new(0x4000, 0x5000..0x6000);
+        let mut reader = PhysReader::new(&memory).with_end(0x4000);
+
+        reader.seek(SeekFrom::Start(0x800)).unwrap();
+        let mut buf = vec![0u8; 0x2000];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, memory.memory[0x800..0x2800]);
+
+        assert_eq!(reader.seek(SeekFrom::End(-0x10)).unwrap(), 0x3ff0);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, memory.memory[0x3ff0..]);
+
+        assert!(reader.seek(SeekFrom::Current(-0x5000)).is_err());
+    }
+
+    #[test]
+    fn unreadable_pages() {
+        let memory = TestMemory::new(0x4000, 0x1000..0x2000);
+
+        let mut reader = PhysReader::new(&memory);
+        let mut buf = vec![0u8; 0x3000];
+        reader.seek(SeekFrom::Start(0x800)).unwrap();
+        reader.read_exact(&mut buf[..0x800]).unwrap();
+        assert_eq!(buf[..0x800], memory.memory[0x800..0x1000]);
+        assert!(reader.read_exact(&mut buf).is_err());
+
+        let mut reader = PhysReader::new(&memory)
+            .with_policy(UnreadablePolicy::ZeroFill)
+            .with_end(0x4000);
+        let mut buf = Vec::new();
+        io::copy(&mut reader, &mut buf).unwrap();
+        let mut expected = memory.memory.clone();
+        expected[0x1000..0x2000].fill(0);
+        assert_eq!(buf, expected);
+        assert_eq!(reader.unreadable(), 0x1000);
+        assert_eq!(reader.zero_filled(), [PhysRange::new(0x1000, 0x1fff)]);
+
+        // Without an end, zero-filling would continue forever
+        let mut reader =
+            PhysReader::new(&memory).with_policy(UnreadablePolicy::ZeroFill);
+        let mut buf = Vec::new();
+        assert_eq!(
+            io::copy(&mut reader, &mut buf).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(buf.len(), 0x1000);
+    }
+
+    #[test]
+    fn virtual_pages() {
+        let mut memory = TestMemory::new(0x4000, 0x3000..0x4000);
+        memory.pages = vec![
+            (0x7000_0000, 0x2000),
+            (0x7000_1000, 0x0000),
+            (0x7000_3000, 0x3000),
+        ];
+
+        assert!(VirtReader::new(&memory, Some(2)).is_err());
+        let mut reader = VirtReader::new(&memory, Some(1)).unwrap();
+        reader.seek(SeekFrom::Start(0x7000_0800)).unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..0x800], memory.memory[0x2800..0x3000]);
+        assert_eq!(buf[0x800..], memory.memory[..0x800]);
+        // Unmapped page
+        assert!(reader.read_exact(&mut buf).is_err());
+
+        let mut reader = VirtReader::new(&memory, Some(1))
+            .unwrap()
+            .with_policy(UnreadablePolicy::ZeroFill);
+        reader.seek(SeekFrom::Start(0x7000_1000)).unwrap();
+        let mut buf = vec![0xffu8; 0x3000];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..0x1000], memory.memory[..0x1000]);
+        // Unmapped and unreadable pages
+        assert!(buf[0x1000..].iter().all(|b| *b == 0));
+        assert_eq!(reader.unreadable(), 0x2000);
+        // cr3 is looked up once per reader
+        assert_eq!(memory.cr3_lookups.get(), 3);
+    }
+}
@@ -0,0 +1,253 @@
+//! Verification of images against the hashes recorded in their manifest
+
+use crate::cli::{ImageFormat, VerifyCli};
+use crate::image::aff4::Aff4Reader;
+use crate::image::lime::{LimeHeader, LIME_HEADER_SIZE};
+use crate::image::manifest::{Hasher, Manifest};
+use crate::image::split::SplitIndex;
+use crate::image::{compress, elf};
+use anyhow::{bail, Context};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+const BUFFER_SIZE: usize = 0x100000;
+
+/// Sequential reader that keeps track of its offset in the image
+struct ImageReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    position: u64,
+}
+
+impl ImageReader<'_> {
+    fn skip_to(&mut self, offset: u64) -> anyhow::Result<()> {
+        if offset < self.position {
+            bail!("Ranges are not stored in ascending order");
+        }
+
+        let size = offset - self.position;
+        let skipped =
+            io::copy(&mut (&mut self.inner).take(size), &mut io::sink())?;
+        if skipped != size {
+            bail!("Image is truncated");
+        }
+        self.position = offset;
+
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.inner.read_exact(buf).context("Image is truncated")?;
+        self.position += buf.len() as u64;
+
+        Ok(())
+    }
+}
+
+pub fn run(cli: &VerifyCli) -> Result<(), Box<dyn Error>> {
+    let manifest = Manifest::load(&cli.manifest)
+        .context(format!("Failed to read manifest {}", cli.manifest))?;
+
+    let image_path = match &cli.image {
+        Some(image) => PathBuf::from(image),
+        None => Path::new(&cli.manifest).with_file_name(&manifest.image),
+    };
+    let open_image = || {
+        File::open(&image_path)
+            .context(format!("Failed to open {}", image_path.display()))
+    };
+
+    // AFF4 images compress individual chunks of their image stream, whose
+    // offsets are used instead of file offsets
+    let (mut image, mut segments) = match manifest.format {
+        ImageFormat::Aff4 => {
+            let reader = Aff4Reader::new(BufReader::new(open_image()?))
+                .context("Failed to read AFF4 container")?;
+            let segments = reader.segments().to_vec();
+            let image = ImageReader {
+                inner: Box::new(reader),
+                position: 0,
+            };
+            (image, segments)
+        }
+        _ => {
+            let input: Box<dyn Read> = match &manifest.index {
+                Some(index) => {
+                    let index_path = image_path.with_file_name(index);
+                    SplitIndex::load(&index_path)
+                        .and_then(|i| i.reader(index_path.parent().unwrap()))
+                        .context(format!(
+                            "Failed to open segments in {}",
+                            index_path.display()
+                        ))?
+                }
+                None => Box::new(BufReader::new(open_image()?)),
+            };
+            let image = ImageReader {
+                inner: compress::decoder(input, manifest.compression)?,
+                position: 0,
+            };
+            (image, Vec::new())
+        }
+    };
+    if manifest.format == ImageFormat::Elf {
+        let (elf_segments, size) = elf::parse_headers(&mut image.inner)?;
+        image.position = size;
+        segments = elf_segments;
+    }
+
+    let algorithms: Vec<_> = manifest.hashes.keys().copied().collect();
+    let mut image_hasher = Hasher::new(&algorithms);
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut mismatches = 0;
+
+    for expected in &manifest.ranges {
+        let range = expected.range();
+        let offset = match manifest.format {
+            ImageFormat::Raw => image.position,
+            ImageFormat::Padded => range.start,
+            ImageFormat::Lime => {
+                let mut header = [0u8; LIME_HEADER_SIZE];
+                image.read_exact(&mut header)?;
+                if LimeHeader::from_bytes(&header)?.range != range {
+                    return Err(
+                        format!("LiME header of {} differs", range).into()
+                    );
+                }
+                image.position
+            }
+            ImageFormat::Elf | ImageFormat::Aff4 => {
+                segments
+                    .iter()
+                    .find(|s| s.range == range)
+                    .context(format!("Image does not contain {}", range))?
+                    .offset
+            }
+        };
+        image.skip_to(offset)?;
+
+        let mut hasher = Hasher::new(&algorithms);
+        let mut done = 0;
+        while done < range.size() {
+            let size = (BUFFER_SIZE as u64).min(range.size() - done) as usize;
+            image.read_exact(&mut buf[..size])?;
+            hasher.update(&buf[..size]);
+            image_hasher.update(&buf[..size]);
+            done += size as u64;
+        }
+
+        if hasher.finalize() == expected.hashes {
+            println!("{}: OK", range);
+        } else {
+            println!("{}: MISMATCH", range);
+            mismatches += 1;
+        }
+    }
+
+    if mismatches != 0 || image_hasher.finalize() != manifest.hashes {
+        return Err(format!(
+            "Image does not match its manifest ({} ranges differ)",
+            mismatches
+        )
+        .into());
+    }
+
+    println!("{}: OK", image_path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acquire;
+    use crate::backend::image::ImageBackend;
+    use crate::cli::{AcquireCli, Compression, HashAlgorithm};
+    use crate::iomem::PhysRange;
+    use std::fs;
+    use std::os::unix::fs::FileExt;
+
+    const MARKER: &[u8] = b"pmem verification marker";
+
+    /// Physical memory with distinct contents in each range
+    fn memory(ranges: &[PhysRange]) -> ImageBackend {
+        let file = tempfile::tempfile().unwrap();
+        for (i, range) in ranges.iter().enumerate() {
+            let data: Vec<u8> = (0..range.size())
+                .map(|j| (i as u64 * 7 + j % 251) as u8)
+                .collect();
+            file.write_all_at(&data, range.start).unwrap();
+        }
+        file.write_all_at(MARKER, ranges[0].start + 0x100).unwrap();
+
+        ImageBackend::detect(file).unwrap()
+    }
+
+    /// Flip a bit of the marker in the image file that contains it
+    fn corrupt(dir: &Path) -> bool {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let mut data = fs::read(&path).unwrap();
+            if let Some(at) =
+                data.windows(MARKER.len()).position(|w| w == MARKER)
+            {
+                data[at] ^= 1;
+                fs::write(&path, data).unwrap();
+                return true;
+            }
+        }
+
+        false
+    }
+
+    #[test]
+    fn round_trip() {
+        let ranges = [
+            PhysRange::new(0x1000, 0x2fff),
+            PhysRange::new(0x5000, 0x5fff),
+        ];
+        let backend = memory(&ranges);
+
+        for (format, compress, split_size) in [
+            (ImageFormat::Raw, None, None),
+            (ImageFormat::Padded, None, None),
+            (ImageFormat::Lime, None, None),
+            (ImageFormat::Elf, None, None),
+            (ImageFormat::Aff4, None, None),
+            (ImageFormat::Aff4, Some(Compression::Gzip), None),
+            (ImageFormat::Raw, Some(Compression::Zstd), None),
+            (ImageFormat::Elf, None, Some(0x1000)),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let output = dir.path().join("memory");
+            let output = output.to_str().unwrap();
+            let cli = AcquireCli {
+                output: output.to_string(),
+                format,
+                sparse: false,
+                compress,
+                level: None,
+                hash: vec![HashAlgorithm::Blake3],
+                split_size,
+                resume: false,
+                zero_fill: false,
+                iomem: String::new(),
+                chunk_size: 0x800,
+            };
+            acquire::acquire(&cli, &backend, &ranges, None).unwrap();
+
+            let verify_cli = VerifyCli {
+                manifest: Manifest::path_for(output),
+                image: None,
+            };
+            run(&verify_cli).unwrap();
+
+            // Compressed images do not contain the marker
+            if compress.is_none() {
+                assert!(corrupt(dir.path()), "{:?}", format);
+                assert!(run(&verify_cli).is_err(), "{:?}", format);
+            }
+        }
+    }
+}
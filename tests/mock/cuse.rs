@@ -0,0 +1,331 @@
+//! Character device in user space (CUSE) that implements the linpmem ioctls
+//!
+//! The device speaks the FUSE protocol over `/dev/cuse` directly. Ioctls are
+//! unrestricted, i.e., the arguments are fetched from the caller in several
+//! rounds: first the request structure, then, for buffer reads, the buffer
+//! that it points to.
+
+use super::machine::{Machine, ACCESS_BUFFER, DIRECT_MAP};
+use nix::errno::Errno;
+use nix::sys::stat;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const CUSE_PATH: &str = "/dev/cuse";
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+const CUSE_UNRESTRICTED_IOCTL: u32 = 1 << 0;
+const FUSE_IOCTL_RETRY: u32 = 1 << 2;
+
+const FUSE_OPEN: u32 = 14;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_IOCTL: u32 = 39;
+const CUSE_INIT: u32 = 4096;
+
+const IN_HEADER_SIZE: usize = 40;
+const IOCTL_IN_SIZE: usize = 32;
+const MAX_WRITE: u32 = 0x20000;
+const READ_BUFFER_SIZE: usize = 0x40000;
+
+/// Largest buffer that is transferred in a single ioctl, larger reads are
+/// short
+pub const MAX_TRANSFER: u64 = 0x10000;
+
+/// Sizes of the request structures of the linpmem ioctls
+const DATA_TRANSFER_SIZE: u64 = 40;
+const VTOP_INFO_SIZE: u64 = 32;
+const CR3_INFO_SIZE: u64 = 16;
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn set_u64_at(data: &mut [u8], at: usize, value: u64) {
+    data[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+enum IoctlReply {
+    /// Fetch the given user memory and repeat the request
+    Retry {
+        in_iovs: Vec<(u64, u64)>,
+        out_iovs: Vec<(u64, u64)>,
+    },
+    /// Copy the data to the output iovecs of the last retry
+    Done(Vec<u8>),
+}
+
+/// Handle a linpmem ioctl, `input` holds the memory of all input iovecs
+fn ioctl(
+    machine: &Machine,
+    cmd: u32,
+    arg: u64,
+    input: &[u8],
+    out_size: u64,
+) -> Result<IoctlReply, Errno> {
+    let request_size = match ((cmd >> 8) as u8, cmd as u8) {
+        (b'a', b'a') => DATA_TRANSFER_SIZE,
+        (b'a', b'b') => VTOP_INFO_SIZE,
+        (b'a', b'c') => CR3_INFO_SIZE,
+        _ => return Err(Errno::ENOTTY),
+    };
+    if (input.len() as u64) < request_size {
+        return Ok(IoctlReply::Retry {
+            in_iovs: vec![(arg, request_size)],
+            out_iovs: vec![(arg, request_size)],
+        });
+    }
+    let mut request = input[..request_size as usize].to_vec();
+
+    match cmd as u8 {
+        b'a' => {
+            let address = u64_at(&request, 0);
+            let access = request[32];
+
+            if access != ACCESS_BUFFER {
+                let value = machine.read_value(address, access)?;
+                set_u64_at(&mut request, 8, value);
+                return Ok(IoctlReply::Done(request));
+            }
+
+            let buffer = u64_at(&request, 16);
+            let size = u64_at(&request, 24).min(MAX_TRANSFER);
+            if out_size < request_size + size {
+                return Ok(IoctlReply::Retry {
+                    in_iovs: vec![(arg, request_size)],
+                    out_iovs: vec![(arg, request_size), (buffer, size)],
+                });
+            }
+
+            let data = machine.read_buffer(address, size)?;
+            set_u64_at(&mut request, 24, data.len() as u64);
+            request.extend(data);
+        }
+        b'b' => {
+            let cr3 = match u64_at(&request, 8) {
+                0 => machine.cr3(0)?,
+                cr3 => cr3,
+            };
+            let (phys, pte) = machine.v_to_p(u64_at(&request, 0), cr3)?;
+            set_u64_at(&mut request, 16, phys);
+            // The driver returns a kernel pointer to the entry
+            set_u64_at(&mut request, 24, DIRECT_MAP + pte);
+        }
+        _ => {
+            let cr3 = machine.cr3(u64_at(&request, 0))?;
+            set_u64_at(&mut request, 8, cr3);
+        }
+    }
+
+    Ok(IoctlReply::Done(request))
+}
+
+struct Channel {
+    file: File,
+}
+
+impl Channel {
+    fn reply(&mut self, unique: u64, result: Result<&[u8], Errno>) {
+        let (error, data) = match result {
+            Ok(data) => (0, data),
+            Err(errno) => (-(errno as i32), &[][..]),
+        };
+        let mut reply = Vec::new();
+
+        reply.extend_from_slice(&(16 + data.len() as u32).to_le_bytes());
+        reply.extend_from_slice(&error.to_le_bytes());
+        reply.extend_from_slice(&unique.to_le_bytes());
+        reply.extend_from_slice(data);
+
+        // The request might have been interrupted in the meantime
+        let _ = self.file.write_all(&reply);
+    }
+
+    fn init(&mut self, name: &str) -> io::Result<()> {
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+        let size = self.file.read(&mut buf)?;
+
+        if size < IN_HEADER_SIZE + 16 || u32_at(&buf, 4) != CUSE_INIT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected CUSE_INIT",
+            ));
+        }
+        let minor = u32_at(&buf, IN_HEADER_SIZE + 4);
+
+        let mut init_out = Vec::new();
+        for value in [
+            FUSE_KERNEL_VERSION,
+            minor.min(FUSE_KERNEL_MINOR_VERSION),
+            0,
+            CUSE_UNRESTRICTED_IOCTL,
+            MAX_WRITE,
+            MAX_WRITE,
+            // dynamic device numbers
+            0,
+            0,
+        ] {
+            init_out.extend_from_slice(&value.to_le_bytes());
+        }
+        init_out.resize(init_out.len() + 10 * 4, 0);
+        init_out.extend_from_slice(format!("DEVNAME={}\0", name).as_bytes());
+
+        self.reply(u64_at(&buf, 8), Ok(&init_out));
+
+        Ok(())
+    }
+
+    fn serve(mut self, machine: Arc<Mutex<Machine>>) {
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+
+        loop {
+            let size = match self.file.read(&mut buf) {
+                Ok(size) => size,
+                Err(err)
+                    if err.raw_os_error() == Some(Errno::ENOENT as i32) =>
+                {
+                    continue
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(_) => return,
+            };
+            if size < IN_HEADER_SIZE {
+                return;
+            }
+
+            let opcode = u32_at(&buf, 4);
+            let unique = u64_at(&buf, 8);
+            let body = &buf[IN_HEADER_SIZE..size];
+
+            match opcode {
+                FUSE_OPEN => self.reply(unique, Ok(&[0u8; 16])),
+                FUSE_RELEASE | FUSE_FLUSH => self.reply(unique, Ok(&[])),
+                FUSE_INTERRUPT => {}
+                FUSE_IOCTL if body.len() >= IOCTL_IN_SIZE => {
+                    let cmd = u32_at(body, 12);
+                    let arg = u64_at(body, 16);
+                    let out_size = u32_at(body, 28) as u64;
+                    let machine = machine.lock().unwrap();
+
+                    let reply = ioctl(
+                        &machine,
+                        cmd,
+                        arg,
+                        &body[IOCTL_IN_SIZE..],
+                        out_size,
+                    );
+                    let data = match reply {
+                        Ok(IoctlReply::Retry { in_iovs, out_iovs }) => {
+                            let mut data = Vec::new();
+                            data.extend_from_slice(&0i32.to_le_bytes());
+                            data.extend_from_slice(
+                                &FUSE_IOCTL_RETRY.to_le_bytes(),
+                            );
+                            data.extend_from_slice(
+                                &(in_iovs.len() as u32).to_le_bytes(),
+                            );
+                            data.extend_from_slice(
+                                &(out_iovs.len() as u32).to_le_bytes(),
+                            );
+                            for (base, len) in in_iovs.iter().chain(&out_iovs) {
+                                data.extend_from_slice(&base.to_le_bytes());
+                                data.extend_from_slice(&len.to_le_bytes());
+                            }
+                            Ok(data)
+                        }
+                        Ok(IoctlReply::Done(output)) => {
+                            let mut data = vec![0u8; 16];
+                            data.extend(output);
+                            Ok(data)
+                        }
+                        Err(errno) => Err(errno),
+                    };
+                    drop(machine);
+
+                    match &data {
+                        Ok(data) => self.reply(unique, Ok(data)),
+                        Err(errno) => self.reply(unique, Err(*errno)),
+                    }
+                }
+                _ => self.reply(unique, Err(Errno::ENOSYS)),
+            }
+        }
+    }
+}
+
+/// Character device that serves requests from a thread until the process
+/// exits
+pub struct CuseDevice {
+    pub path: PathBuf,
+    /// Whether the device file was created by us
+    created: bool,
+}
+
+impl CuseDevice {
+    /// Register the device `/dev/<name>`
+    ///
+    /// Requires root privileges and a kernel with CUSE support.
+    pub fn spawn(name: &str, machine: Arc<Mutex<Machine>>) -> io::Result<Self> {
+        let path = Path::new("/dev").join(name);
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+
+        let mut channel = Channel {
+            file: File::options().read(true).write(true).open(CUSE_PATH)?,
+        };
+        channel.init(name)?;
+        thread::spawn(move || channel.serve(machine));
+
+        // Without devtmpfs, the device file has to be created manually
+        let created = !path.exists();
+        if created {
+            let numbers = fs::read_to_string(
+                Path::new("/sys/class/cuse").join(name).join("dev"),
+            )?;
+            let (major, minor) =
+                numbers.trim().split_once(':').ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Malformed device numbers",
+                    )
+                })?;
+            let parse = |n: &str| {
+                n.parse::<u64>().map_err(|err| {
+                    io::Error::new(io::ErrorKind::InvalidData, err)
+                })
+            };
+
+            stat::mknod(
+                &path,
+                stat::SFlag::S_IFCHR,
+                stat::Mode::S_IRUSR | stat::Mode::S_IWUSR,
+                stat::makedev(parse(major)?, parse(minor)?),
+            )?;
+        }
+
+        Ok(Self { path, created })
+    }
+}
+
+impl Drop for CuseDevice {
+    fn drop(&mut self) {
+        if self.created {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Synthetic machine that answers the requests of the linpmem ioctls
+//!
+//! Physical memory is a small buffer with a known pattern and a single set of
+//! 4-level page tables that is used by all processes.
+
+use nix::errno::Errno;
+use std::ops::Range;
+
+pub const MEMORY_SIZE: u64 = 0x400000;
+/// Page tables of init and the process that issues requests
+pub const INIT_CR3: u64 = 0x1000;
+pub const INIT_PID: u32 = 1;
+/// 4K page that is mapped to `PHYS_4K`
+pub const VIRT_4K: u64 = 0x0000_5555_5555_4000;
+pub const PHYS_4K: u64 = 0x10000;
+/// 2M page that is mapped to `PHYS_2M`
+pub const VIRT_2M: u64 = 0x0000_7f00_0020_0000;
+pub const PHYS_2M: u64 = 0x200000;
+/// Kernel mapping of all physical memory with 2M pages
+pub const DIRECT_MAP: u64 = 0xffff_8880_0000_0000;
+
+const PAGE_SIZE: u64 = 0x1000;
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_LARGE: u64 = 1 << 7;
+const PTE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Access types of the read request
+pub const ACCESS_BYTE: u8 = 1;
+pub const ACCESS_WORD: u8 = 2;
+pub const ACCESS_DWORD: u8 = 4;
+pub const ACCESS_QWORD: u8 = 8;
+pub const ACCESS_BUFFER: u8 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Reads that touch the physical range fail with EFAULT
+    Efault(Range<u64>),
+    /// Buffer reads return at most this many bytes
+    ShortRead(u64),
+}
+
+pub struct Machine {
+    memory: Vec<u8>,
+    /// Next free page for page tables
+    next_table: u64,
+    pub faults: Vec<Fault>,
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        let mut machine = Self {
+            memory: (0..MEMORY_SIZE)
+                .map(|a| (a ^ (a >> 8) ^ (a >> 16)) as u8)
+                .collect(),
+            next_table: INIT_CR3,
+            faults: Vec::new(),
+        };
+
+        machine.new_table();
+        machine.map(VIRT_4K, PHYS_4K, 1);
+        machine.map(VIRT_2M, PHYS_2M, 2);
+        for phys in (0..MEMORY_SIZE).step_by(0x200000) {
+            machine.map(DIRECT_MAP + phys, phys, 2);
+        }
+
+        machine
+    }
+
+    /// Contents of physical memory
+    pub fn memory(&self, range: Range<u64>) -> &[u8] {
+        &self.memory[range.start as usize..range.end as usize]
+    }
+
+    fn new_table(&mut self) -> u64 {
+        let table = self.next_table;
+
+        self.memory[table as usize..(table + PAGE_SIZE) as usize].fill(0);
+        self.next_table += PAGE_SIZE;
+
+        table
+    }
+
+    fn entry(&self, address: u64) -> u64 {
+        let at = address as usize;
+
+        u64::from_le_bytes(self.memory[at..at + 8].try_into().unwrap())
+    }
+
+    fn set_entry(&mut self, address: u64, value: u64) {
+        let at = address as usize;
+
+        self.memory[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Map `virt` to `phys` with a page that is mapped at `level`, i.e., 1
+    /// for 4K and 2 for 2M pages
+    fn map(&mut self, virt: u64, phys: u64, level: u32) {
+        let mut table = INIT_CR3;
+
+        for current in (level..=4).rev() {
+            let index = (virt >> (12 + 9 * (current - 1))) & 0x1ff;
+            let entry = table + index * 8;
+
+            if current == level {
+                let large = if level > 1 { PTE_LARGE } else { 0 };
+                self.set_entry(
+                    entry,
+                    phys | PTE_PRESENT | PTE_WRITE | PTE_USER | large,
+                );
+            } else {
+                if self.entry(entry) & PTE_PRESENT == 0 {
+                    let next = self.new_table();
+                    self.set_entry(
+                        entry,
+                        next | PTE_PRESENT | PTE_WRITE | PTE_USER,
+                    );
+                }
+                table = self.entry(entry) & PTE_ADDRESS_MASK;
+            }
+        }
+    }
+
+    fn check(&self, address: u64, size: u64) -> Result<(), Errno> {
+        let end = address.checked_add(size).ok_or(Errno::EINVAL)?;
+
+        if end > MEMORY_SIZE {
+            return Err(Errno::EINVAL);
+        }
+        for fault in &self.faults {
+            if let Fault::Efault(range) = fault {
+                if address < range.end && range.start < end {
+                    return Err(Errno::EFAULT);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a value of the given access type
+    pub fn read_value(&self, address: u64, access: u8) -> Result<u64, Errno> {
+        let size = match access {
+            ACCESS_BYTE | ACCESS_WORD | ACCESS_DWORD | ACCESS_QWORD => {
+                access as u64
+            }
+            _ => return Err(Errno::EINVAL),
+        };
+        self.check(address, size)?;
+
+        let mut value = [0u8; 8];
+        value[..size as usize]
+            .copy_from_slice(self.memory(address..address + size));
+
+        Ok(u64::from_le_bytes(value))
+    }
+
+    /// Read a buffer, which might be shorter than requested
+    pub fn read_buffer(
+        &self,
+        address: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Errno> {
+        let mut size = size;
+        for fault in &self.faults {
+            if let Fault::ShortRead(limit) = fault {
+                size = size.min(*limit);
+            }
+        }
+        self.check(address, size)?;
+
+        Ok(self.memory(address..address + size).to_vec())
+    }
+
+    /// Translate `virt` using the page tables at `cr3`
+    ///
+    /// Returns the physical address and the address of the last entry.
+    pub fn v_to_p(&self, virt: u64, cr3: u64) -> Result<(u64, u64), Errno> {
+        let mut table = cr3 & PTE_ADDRESS_MASK;
+
+        for level in (1..=4).rev() {
+            let shift = 12 + 9 * (level - 1);
+            let entry_address = table + ((virt >> shift) & 0x1ff) * 8;
+            self.check(entry_address, 8)?;
+            let entry = self.entry(entry_address);
+
+            if entry & PTE_PRESENT == 0 {
+                return Err(Errno::EINVAL);
+            }
+            if level == 1 || entry & PTE_LARGE != 0 {
+                let offset_mask = (1 << shift) - 1;
+                let phys = (entry & PTE_ADDRESS_MASK & !offset_mask)
+                    | (virt & offset_mask);
+                return Ok((phys, entry_address));
+            }
+            table = entry & PTE_ADDRESS_MASK;
+        }
+
+        unreachable!()
+    }
+
+    /// Value of cr3 of process `pid`, 0 is the process that issues requests
+    pub fn cr3(&self, pid: u64) -> Result<u64, Errno> {
+        match pid {
+            0 => Ok(INIT_CR3),
+            pid if pid == INIT_PID as u64 => Ok(INIT_CR3),
+            _ => Err(Errno::ESRCH),
+        }
+    }
+}
@@ -0,0 +1,4 @@
+//! Mock linpmem device for end-to-end tests without the kernel module
+
+pub mod cuse;
+pub mod machine;
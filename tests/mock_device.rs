@@ -0,0 +1,225 @@
+//! End-to-end tests of the `pmem` binary against a mock linpmem device
+//!
+//! The device is created with CUSE, which requires root privileges and a
+//! kernel with CUSE support. Tests that need the device are skipped if
+//! `/dev/cuse` cannot be opened, unless `PMEM_REQUIRE_CUSE` is set, e.g., in
+//! CI. The `pmem` binary is pointed at the device with `PMEM_DEVICE`, thus,
+//! the tests also run while the real driver is loaded.
+
+mod mock;
+
+use mock::cuse::{CuseDevice, CUSE_PATH, MAX_TRANSFER};
+use mock::machine::*;
+use nix::errno::Errno;
+use pmem::ioctl::DEVICE_ENV;
+use std::env;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::{self, Command, Output};
+use std::sync::{Arc, Mutex};
+
+/// Fail instead of skipping tests that need `/dev/cuse`
+const REQUIRE_CUSE_ENV: &str = "PMEM_REQUIRE_CUSE";
+
+/// Whether the tests that need a mock device can run
+fn cuse_available() -> bool {
+    match File::options().read(true).write(true).open(CUSE_PATH) {
+        Ok(_) => true,
+        Err(err) if env::var_os(REQUIRE_CUSE_ENV).is_none() => {
+            eprintln!("Skipping, cannot open {}: {}", CUSE_PATH, err);
+            false
+        }
+        Err(err) => panic!("Cannot open {}: {}", CUSE_PATH, err),
+    }
+}
+
+fn pmem(device: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pmem"))
+        .env(DEVICE_ENV, device)
+        .args(["--backend", "linpmem"])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+#[test]
+fn machine_translations() {
+    let machine = Machine::new();
+
+    assert_eq!(machine.cr3(INIT_PID as u64), Ok(INIT_CR3));
+    assert_eq!(machine.cr3(4242), Err(Errno::ESRCH));
+    assert_eq!(
+        machine.v_to_p(VIRT_4K + 0x123, INIT_CR3).unwrap().0,
+        PHYS_4K + 0x123
+    );
+    assert_eq!(
+        machine.v_to_p(VIRT_2M + 0x12345, INIT_CR3).unwrap().0,
+        PHYS_2M + 0x12345
+    );
+    assert_eq!(machine.v_to_p(0x1000, INIT_CR3), Err(Errno::EINVAL));
+    assert_eq!(
+        machine.v_to_p(DIRECT_MAP + 0x10123, INIT_CR3).unwrap().0,
+        0x10123
+    );
+}
+
+#[test]
+fn machine_faults() {
+    let mut machine = Machine::new();
+
+    assert_eq!(machine.read_buffer(0x10000, 0x2000).unwrap().len(), 0x2000);
+    assert_eq!(machine.read_buffer(MEMORY_SIZE - 1, 2), Err(Errno::EINVAL));
+
+    machine.faults.push(Fault::ShortRead(0x800));
+    assert_eq!(machine.read_buffer(0x10000, 0x2000).unwrap().len(), 0x800);
+
+    machine.faults.push(Fault::Efault(0x11000..0x12000));
+    assert_eq!(machine.read_buffer(0x10c00, 0x800), Err(Errno::EFAULT));
+    assert_eq!(
+        machine.read_value(0x11ff8, ACCESS_QWORD),
+        Err(Errno::EFAULT)
+    );
+    assert!(machine.read_value(0x12000, ACCESS_QWORD).is_ok());
+}
+
+#[test]
+fn cuse_device() {
+    if !cuse_available() {
+        return;
+    }
+
+    let machine = Arc::new(Mutex::new(Machine::new()));
+    let name = format!("linpmem-mock-{}", process::id());
+    let device = CuseDevice::spawn(&name, machine.clone())
+        .unwrap_or_else(|err| panic!("Failed to create mock device: {}", err));
+    let pmem = |args: &[&str]| pmem(&device.path, args);
+    let expected = |address: u64, size: u64| {
+        machine
+            .lock()
+            .unwrap()
+            .memory(address..address + size)
+            .to_vec()
+    };
+
+    // cr3 and translations
+    assert_eq!(stdout(&pmem(&["--cr3", "-p", "1"])), "0x0000000000001000");
+    assert_eq!(
+        stdout(&pmem(&["-v", &format!("0x{:x}", VIRT_4K + 0x123)])),
+        format!("0x{:016x}", PHYS_4K + 0x123)
+    );
+    assert_eq!(
+        stdout(&pmem(&[
+            "-v",
+            &format!("0x{:x}", VIRT_2M + 0x1234),
+            "-p",
+            "1"
+        ])),
+        format!("0x{:016x}", PHYS_2M + 0x1234)
+    );
+    assert!(!pmem(&["-v", "0x1000"]).status.success());
+
+    // Page-table entries
+    let output =
+        stdout(&pmem(&["-v", &format!("0x{:x}", VIRT_4K), "--show-pte"]));
+    assert!(output.contains("page size 4K"), "{}", output);
+    assert!(
+        output.contains("flags: present, writable, user"),
+        "{}",
+        output
+    );
+    let output =
+        stdout(&pmem(&["-v", &format!("0x{:x}", VIRT_2M), "--show-pte"]));
+    assert!(
+        output.contains("page frame 0x200, page size 2M"),
+        "{}",
+        output
+    );
+    assert!(!pmem(&["--cr3", "-p", "4242"]).status.success());
+
+    // Value and buffer reads, which are larger than a single transfer
+    let output = pmem(&["-a", "0x10008", "-m", "qword"]);
+    stdout(&output);
+    assert_eq!(output.stdout, expected(0x10008, 8));
+    let size = 3 * MAX_TRANSFER + 0x10;
+    let output =
+        pmem(&["-a", "0x10000", "-m", "buffer", "-s", &size.to_string()]);
+    stdout(&output);
+    assert_eq!(output.stdout, expected(0x10000, size));
+
+    // Short reads are continued
+    machine.lock().unwrap().faults = vec![Fault::ShortRead(0x800)];
+    let output = pmem(&["-a", "0x10000", "-m", "buffer", "-s", "0x3000"]);
+    stdout(&output);
+    assert_eq!(output.stdout, expected(0x10000, 0x3000));
+
+    // Errors are reported
+    machine.lock().unwrap().faults = vec![Fault::Efault(0x11000..0x12000)];
+    let output = pmem(&["-a", "0x10000", "-m", "buffer", "-s", "0x3000"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Failed to read 0x0000000000010000"));
+
+    // Reads from virtual memory, which cross a page boundary
+    let output = pmem(&[
+        "read",
+        "--pid",
+        "1",
+        "--virt",
+        &format!("0x{:x}", VIRT_2M + 0xff0),
+        "-s",
+        "0x20",
+    ]);
+    stdout(&output);
+    assert_eq!(output.stdout, expected(PHYS_2M + 0xff0, 0x20));
+    let output = pmem(&["read", "--virt", "0x1000", "-s", "0x10"]);
+    assert_eq!(output.status.code(), Some(5));
+    let output =
+        pmem(&["read", "--virt", "0x1000", "-s", "0x10", "--zero-fill"]);
+    stdout(&output);
+    assert_eq!(output.stdout, vec![0u8; 0x10]);
+
+    // Acquisitions fail on unreadable pages unless asked to zero-fill them
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let iomem = dir.join("iomem");
+    let image = dir.join("memory.raw");
+    fs::write(&iomem, "00010000-00013fff : System RAM\n").unwrap();
+    let acquire = |zero_fill: bool| {
+        let mut args = vec![
+            "acquire",
+            "--progress=json",
+            "--iomem",
+            iomem.to_str().unwrap(),
+            image.to_str().unwrap(),
+        ];
+        if zero_fill {
+            args.push("--zero-fill");
+        }
+        pmem(&args)
+    };
+    let output = acquire(false);
+    assert_eq!(output.status.code(), Some(1));
+    let output = acquire(true);
+    assert_eq!(output.status.code(), Some(1));
+    let mut expected_image = expected(0x10000, 0x4000);
+    expected_image[0x1000..0x2000].fill(0);
+    assert_eq!(fs::read(&image).unwrap(), expected_image);
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("\"ranges_failed\":1")
+    );
+    let manifest =
+        fs::read_to_string(dir.join("memory.raw.manifest.json")).unwrap();
+    assert!(manifest.contains("\"zero_filled\""));
+
+    drop(device);
+}